@@ -1,8 +1,9 @@
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 
 use crate::{discord::relay, DATABASE};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     /// Source, e.g matrix, discord
     pub source: String,
@@ -17,7 +18,7 @@ pub struct User {
     pub avatar: Option<String>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Message {
     pub service: String,
     /// Server id, if applicable (not applicable to matrix as it can only work as 1 appservice atm)
@@ -26,13 +27,60 @@ pub struct Message {
     pub id: String,
 }
 
-#[derive(Clone)]
+/// What a message is replying to, for services that can't show replies natively
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplyPreview {
+    /// Author of the replied to message
+    pub user: User,
+    /// Start of the replied to message, or what kind of media it is
+    pub excerpt: String,
+    /// Whether the author of the replied to message should be pinged
+    pub mention: bool,
+}
+
+/// A sticker sent as a message
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sticker {
+    pub name: String,
+    /// Where the sticker's image can be downloaded, an mxc URI for Matrix stickers
+    pub url: String,
+    /// Lottie animations have to be converted to an image before they can be shown
+    pub lottie: bool,
+}
+
+/// A file sent with a message
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub url: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FullMessage {
     pub user: User,
     pub message: Message,
 
     pub content: String,
     pub reply: Option<Box<Message>>,
+    pub reply_preview: Option<ReplyPreview>,
+    /// When the message was originally sent, in milliseconds since the unix epoch
+    pub timestamp: Option<i64>,
+    pub sticker: Option<Sticker>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+/// First line of a message, shortened to fit in a reply preview.
+pub fn excerpt(text: &str) -> String {
+    const MAX_LENGTH: usize = 64;
+
+    let line = text.lines().next().unwrap_or_default();
+    if line.chars().count() > MAX_LENGTH {
+        return format!("{}...", line.chars().take(MAX_LENGTH).collect::<String>());
+    }
+    line.to_owned()
 }
 
 pub fn create_message(source: Message, relayed: Message) {
@@ -42,6 +90,141 @@ pub fn create_message(source: Message, relayed: Message) {
     (source.service, source.server_id, source.room_id, source.id, relayed.service, relayed.server_id, relayed.room_id, relayed.id)).expect("Failed to insert message into database!");
 }
 
+/// Records who sent a message, so everything they sent can be found if they
+/// ask to be forgotten.
+pub fn set_message_author(source: &Message, user_id: &str) {
+    DATABASE
+        .lock()
+        .execute(
+            "UPDATE messages SET author_org=? WHERE service_org=? AND room_id_org=? AND id_org=?",
+            [user_id, &source.service, &source.room_id, &source.id],
+        )
+        .expect("Failed to save message author to database!");
+}
+
+/// Saves the Matrix reaction a Discord user's reaction was relayed as.
+pub fn save_reaction(reacted: &Message, user_id: &str, key: &str, relayed: &Message) {
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO reactions (message_id, user_id, key, room_id, event_id) VALUES (?, ?, ?, ?, ?)",
+            [&reacted.id, user_id, key, &relayed.room_id, &relayed.id],
+        )
+        .expect("Failed to save reaction to database!");
+}
+
+/// Forgets a relayed reaction, returning what it was relayed as.
+pub fn take_reaction(reacted: &Message, user_id: &str, key: &str) -> Option<Message> {
+    let database = DATABASE.lock();
+    let relayed = database
+        .query_row(
+            "SELECT room_id, event_id FROM reactions WHERE message_id=? AND user_id=? AND key=?",
+            [&reacted.id, user_id, key],
+            |row| {
+                Ok(Message {
+                    service: "matrix".to_owned(),
+                    server_id: "".to_owned(),
+                    room_id: row.get(0)?,
+                    id: row.get(1)?,
+                })
+            },
+        )
+        .ok()?;
+    let _ = database.execute(
+        "DELETE FROM reactions WHERE message_id=? AND user_id=? AND key=?",
+        [&reacted.id, user_id, key],
+    );
+    Some(relayed)
+}
+
+/// Forgets every reaction a user sent, returning what they were relayed as.
+pub fn take_user_reactions(user_id: &str) -> Vec<Message> {
+    let database = DATABASE.lock();
+    let reactions = {
+        let mut stmt = database
+            .prepare("SELECT room_id, event_id FROM reactions WHERE user_id=?")
+            .unwrap();
+        let reactions = stmt
+            .query_map([user_id], |row| {
+                Ok(Message {
+                    service: "matrix".to_owned(),
+                    server_id: "".to_owned(),
+                    room_id: row.get(0)?,
+                    id: row.get(1)?,
+                })
+            })
+            .unwrap()
+            .filter_map(|reaction| reaction.ok())
+            .collect();
+        reactions
+    };
+    let _ = database.execute("DELETE FROM reactions WHERE user_id=?", [user_id]);
+    reactions
+}
+
+/// Saves a Matrix reaction that was relayed to Discord as `reaction`.
+pub fn save_matrix_reaction(reaction: &Message, user_id: &str, reacted: &Message, key: &str) {
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO matrix_reactions (event_id, room_id, user_id, reacted_id, reaction) VALUES (?, ?, ?, ?, ?)",
+            [&reaction.id, &reaction.room_id, user_id, &reacted.id, key],
+        )
+        .expect("Failed to save reaction to database!");
+}
+
+/// Forgets a relayed Matrix reaction, returning the message it was on and what
+/// it was relayed as, and whether anyone else still reacts the same way.
+pub fn take_matrix_reaction(reaction: &Message) -> Option<(Message, String, bool)> {
+    let database = DATABASE.lock();
+    let (reacted_id, key): (String, String) = database
+        .query_row(
+            "SELECT reacted_id, reaction FROM matrix_reactions WHERE event_id=? AND room_id=?",
+            [&reaction.id, &reaction.room_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .ok()?;
+    let _ = database.execute(
+        "DELETE FROM matrix_reactions WHERE event_id=? AND room_id=?",
+        [&reaction.id, &reaction.room_id],
+    );
+    let shared = database
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM matrix_reactions WHERE room_id=? AND reacted_id=? AND reaction=?)",
+            [&reaction.room_id, &reacted_id, &key],
+            |row| row.get(0),
+        )
+        .unwrap_or(false);
+    let reacted = Message {
+        service: "matrix".to_owned(),
+        server_id: "".to_owned(),
+        room_id: reaction.room_id.clone(),
+        id: reacted_id,
+    };
+    Some((reacted, key, shared))
+}
+
+/// The relayed reactions a Matrix user sent.
+pub fn matrix_user_reactions(user_id: &str) -> Vec<Message> {
+    let database = DATABASE.lock();
+    let mut stmt = database
+        .prepare("SELECT room_id, event_id FROM matrix_reactions WHERE user_id=?")
+        .unwrap();
+    let reactions = stmt
+        .query_map([user_id], |row| {
+            Ok(Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: row.get(0)?,
+                id: row.get(1)?,
+            })
+        })
+        .unwrap()
+        .filter_map(|reaction| reaction.ok())
+        .collect();
+    reactions
+}
+
 pub fn message_origin(relayed: Message) -> Option<Message> {
     println!(
         "Origin of: {} {} {} {}",
@@ -82,6 +265,24 @@ pub fn message_origin(relayed: Message) -> Option<Message> {
     return Some(out[0].clone());
 }
 
+/// Whether a message has already been relayed anywhere, used to avoid relaying
+/// the same event twice when resuming after a restart.
+pub fn message_relayed(source: Message) -> bool {
+    message_relays(source).len() > 0
+}
+
+/// Whether anything has been relayed from or to a room yet.
+pub fn room_has_messages(room_id: &str) -> bool {
+    let database = DATABASE.lock();
+    database
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM messages WHERE room_id_org=:rid OR room_id_out=:rid)",
+            &[(":rid", room_id)],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
 pub fn message_relays(source: Message) -> Vec<Message> {
     let database = DATABASE.lock();
     let mut stmt = database.prepare("SELECT service_out, server_id_out, room_id_out, id_out FROM messages WHERE service_org=:s AND server_id_org=:sid AND room_id_org=:rid AND id_org=:id").unwrap();
@@ -112,6 +313,19 @@ pub fn message_relays(source: Message) -> Vec<Message> {
     return out;
 }
 
+/// Finds the copy of a message on another service, whether the message was
+/// relayed there or originally came from there.
+pub fn message_on_service(msg: Message, service: &str) -> Option<Message> {
+    let relayed = message_relays(msg.clone())
+        .into_iter()
+        .find(|relayed| relayed.service == service);
+    if relayed.is_some() {
+        return relayed;
+    }
+
+    message_origin(msg).filter(|origin| origin.service == service)
+}
+
 pub fn delete_message(msg: Message) {
     let mut id = msg.id.clone();
     let origin = message_origin(msg.clone());
@@ -125,3 +339,70 @@ pub fn delete_message(msg: Message) {
         (":id", id),
     ); // should ignore errors (e.g if message didn't exist in db)
 }
+
+pub fn get_state(key: &str) -> Option<String> {
+    let database = DATABASE.lock();
+    database
+        .query_row(
+            "SELECT value FROM bridge_state WHERE key=:key",
+            &[(":key", key)],
+            |row| row.get(0),
+        )
+        .ok()
+}
+
+pub fn set_state(key: &str, value: &str) {
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO bridge_state (key, value) VALUES (?, ?)",
+            (key, value),
+        )
+        .expect("Failed to save state to database!");
+}
+
+/// Whether a user is banned in a bridged room, `room_id` being the Matrix room.
+pub fn is_banned(service: &str, user_id: &str, room_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM bans WHERE service=? AND user_id=? AND room_id=?)",
+            [service, user_id, room_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+pub fn set_banned(service: &str, user_id: &str, room_id: &str, banned: bool) {
+    let database = DATABASE.lock();
+    let query = match banned {
+        true => "INSERT OR IGNORE INTO bans (service, user_id, room_id) VALUES (?, ?, ?)",
+        false => "DELETE FROM bans WHERE service=? AND user_id=? AND room_id=?",
+    };
+    database
+        .execute(query, [service, user_id, room_id])
+        .expect("Failed to save ban to database!");
+}
+
+/// Records a moderation action in the audit log. `source` is the service the
+/// action was taken on, e.g a ban on Discord is recorded as "discord" "ban".
+pub fn log_moderation(
+    source: &str,
+    action: &str,
+    user_id: &str,
+    room_id: &str,
+    reason: Option<&str>,
+) {
+    println!("Moderation on {source}: {action} {user_id} in {room_id}");
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT INTO moderation_log (time, source, action, user_id, room_id, reason) VALUES (?, ?, ?, ?, ?, ?)",
+            (time, source, action, user_id, room_id, reason),
+        )
+        .expect("Failed to save moderation action to database!");
+}
@@ -1,23 +1,55 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
 use serenity::http::Http;
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::{Interaction, InteractionResponseType};
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
-use serenity::model::prelude::{ChannelId, MessageId, MessageUpdateEvent};
+use serenity::model::prelude::{
+    Channel, ChannelId, Embed, Emoji, EmojiId, Guild, Member, MessageId, MessageUpdateEvent,
+    Permissions, Reaction, ReactionType, Role, RoleId, StickerFormatType, UserId, WebhookId,
+};
 use serenity::prelude::*;
 use serenity::{async_trait, model::prelude::GuildId};
 
 use crate::{
-    chat_service::{self, FullMessage, User},
+    chat_service::{self, Attachment, FullMessage, ReplyPreview, Sticker, User},
     CONFIG,
 };
-use crate::{matrix, Entry, DATABASE};
+use crate::{filter, link, matrix, privacy, Entry, FilterAction, PowerLevelRule, DATABASE};
+
+use super::relay;
 
 struct Handler;
 
 lazy_static! {
     pub static ref CONTEXT: parking_lot::Mutex<Option<Context>> = parking_lot::Mutex::new(None);
+    /// Channels that have been caught up on since the bridge started, live
+    /// messages in other channels wait so they're relayed after what was missed
+    static ref CAUGHT_UP: parking_lot::Mutex<HashSet<u64>> = parking_lot::Mutex::new(HashSet::new());
+    static ref CAUGHT_UP_NOTIFY: tokio::sync::Notify = tokio::sync::Notify::new();
+    /// Held while relaying in a channel, so a message isn't relayed twice by
+    /// catching up and live at the same time
+    static ref CHANNEL_LOCKS: parking_lot::Mutex<HashMap<u64, Arc<tokio::sync::Mutex<()>>>> =
+        parking_lot::Mutex::new(HashMap::new());
+    /// Tasks waiting for a member's timeout to end, by guild and user
+    static ref TIMEOUT_TASKS: parking_lot::Mutex<HashMap<(u64, u64), tokio::task::JoinHandle<()>>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+/// Matrix events can be handled before the Discord bot is ready, e.g. when
+/// resuming a sync after a restart, so this waits for the context to be set.
+pub async fn get_context() -> Context {
+    loop {
+        if let Some(ctx) = CONTEXT.lock().clone() {
+            return ctx;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
 }
 
 // I pass guild id as argument as replies do not have guild id correctly set
@@ -31,6 +63,609 @@ fn message_to_relayed_message(msg: Message, guild_id: String) -> chat_service::M
     return relay_msg;
 }
 
+/// Milliseconds since the unix epoch the message was sent at, taken from the snowflake
+pub fn message_id_timestamp(id: MessageId) -> i64 {
+    const DISCORD_EPOCH: u64 = 1420070400000;
+    ((id.0 >> 22) + DISCORD_EPOCH) as i64
+}
+
+fn last_relayed_message_key(channel_id: u64) -> String {
+    format!("discord_last_message_{channel_id}")
+}
+
+fn last_relayed_message(channel_id: u64) -> Option<MessageId> {
+    chat_service::get_state(&last_relayed_message_key(channel_id))
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(MessageId)
+}
+
+fn set_last_relayed_message(channel_id: u64, message_id: MessageId) {
+    if last_relayed_message(channel_id).map_or(false, |last| last >= message_id) {
+        return;
+    }
+    chat_service::set_state(
+        &last_relayed_message_key(channel_id),
+        &message_id.to_string(),
+    );
+}
+
+fn channel_lock(channel_id: u64) -> Arc<tokio::sync::Mutex<()>> {
+    CHANNEL_LOCKS.lock().entry(channel_id).or_default().clone()
+}
+
+/// Waits until a channel has been caught up on, if it's bridged.
+async fn wait_for_catch_up(channel_id: u64) {
+    if !CONFIG.room.iter().any(|room| room.discord == channel_id) {
+        return;
+    }
+    loop {
+        // Created before checking so a notification in between isn't missed
+        let notified = CAUGHT_UP_NOTIFY.notified();
+        if CAUGHT_UP.lock().contains(&channel_id) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Relays a message sent live, after anything missed in its channel.
+async fn relay_live_message(msg: Message) {
+    wait_for_catch_up(msg.channel_id.0).await;
+    let lock = channel_lock(msg.channel_id.0);
+    let _guard = lock.lock().await;
+    relay_discord_message(msg).await;
+}
+
+/// Relays a message in a bridged channel. Callers hold the channel's lock.
+async fn relay_discord_message(mut msg: Message) {
+    let room = CONFIG
+        .room
+        .iter()
+        .find(|room| room.discord == msg.channel_id.0);
+    let Some(room) = room else {
+        return;
+    };
+    let ctx = (*CONTEXT.lock()).clone().unwrap();
+    if !should_relay(&ctx, room, &msg) {
+        return;
+    }
+    // The puppet of a user banned on Matrix can't send anything there
+    if chat_service::is_banned("discord", &msg.author.id.to_string(), &room.matrix) {
+        return;
+    }
+    if privacy::is_opted_out("discord", &msg.author.id.to_string()) {
+        if privacy::take_notice("discord", &msg.author.id.to_string()) {
+            let notice = format!(
+                "{}'s messages aren't bridged to Matrix, as they opted out",
+                msg.author.name
+            );
+            if let Err(err) = msg.channel_id.say(&ctx.http, notice).await {
+                println!("Failed to send opt out notice: {}", err);
+            }
+        }
+        return;
+    }
+    let puppet_id = matrix::puppet::user_id(&msg.author.id.to_string());
+    if puppet_id.map_or(false, |puppet_id| matrix::policy::is_banned(&puppet_id)) {
+        println!(
+            "Not relaying message from {}, banned by policy",
+            msg.author.id
+        );
+        return;
+    }
+    // Messages fetched from the channel history don't have the guild id set
+    if msg.guild_id.is_none() {
+        msg.guild_id = Some(GuildId(room.discord_guild));
+    }
+
+    let relay_msg = message_to_full_message(msg.clone()).await;
+    // Catching up can overlap with messages that were relayed live
+    if chat_service::message_relayed(relay_msg.message.clone()) {
+        return;
+    }
+    // e.g only a link preview, or a kind of embed that isn't shown
+    if relay_msg.content.is_empty()
+        && relay_msg.sticker.is_none()
+        && relay_msg.attachments.is_empty()
+    {
+        set_last_relayed_message(room.discord, msg.id);
+        return;
+    }
+    if !filter::screen(&relay_msg, 0, false).await {
+        if CONFIG.filter.action == FilterAction::Redact {
+            if let Err(err) = msg.delete(&ctx.http).await {
+                println!("Failed to delete filtered message: {}", err);
+            }
+        }
+        set_last_relayed_message(room.discord, msg.id);
+        return;
+    }
+    let relayed = match matrix::relay::relay_message(relay_msg.clone()).await {
+        Ok(relayed) => relayed,
+        Err(err) => {
+            println!("Failed to relay message {}: {}", msg.id, err);
+            return;
+        }
+    };
+
+    chat_service::create_message(relay_msg.message.clone(), relayed);
+    chat_service::set_message_author(&relay_msg.message, &relay_msg.user.id);
+    set_last_relayed_message(room.discord, msg.id);
+}
+
+/// Relays a DM sent to the bot to the Discord user's DM room on Matrix.
+async fn relay_dm(msg: Message) {
+    let Some(matrix_user) = CONFIG
+        .dm_users
+        .first()
+        .and_then(|user_id| ruma::UserId::parse(user_id).ok())
+    else {
+        return;
+    };
+    if msg.author.bot || privacy::is_opted_out("discord", &msg.author.id.to_string()) {
+        return;
+    }
+    // DMs go through the same checks as bridged channels, apart from deleting
+    // filtered messages, which the bot can't do in DMs
+    let puppet_id = matrix::puppet::user_id(&msg.author.id.to_string());
+    if puppet_id.map_or(false, |puppet_id| matrix::policy::is_banned(&puppet_id)) {
+        println!("Not relaying DM from {}, banned by policy", msg.author.id);
+        return;
+    }
+
+    let user = author_to_user(msg.author.clone()).await;
+    let room_id = match matrix::dm::get_or_create_room(&user, msg.channel_id.0, &matrix_user).await
+    {
+        Ok(room_id) => room_id,
+        Err(err) => {
+            println!("Failed to get DM room for {}: {}", user.tag, err);
+            return;
+        }
+    };
+    if chat_service::is_banned("discord", &user.id, room_id.as_str()) {
+        return;
+    }
+
+    let relay_msg = message_to_full_message(msg).await;
+    if chat_service::message_relayed(relay_msg.message.clone())
+        || (relay_msg.content.is_empty()
+            && relay_msg.sticker.is_none()
+            && relay_msg.attachments.is_empty())
+    {
+        return;
+    }
+    if !filter::screen(&relay_msg, 0, false).await {
+        return;
+    }
+    let relayed = match matrix::relay::relay_message(relay_msg.clone()).await {
+        Ok(relayed) => relayed,
+        Err(err) => {
+            println!("Failed to relay DM from {}: {}", user.tag, err);
+            return;
+        }
+    };
+    chat_service::create_message(relay_msg.message.clone(), relayed);
+    chat_service::set_message_author(&relay_msg.message, &relay_msg.user.id);
+}
+
+fn to_emote(emoji: &Emoji) -> matrix::emotes::Emoji {
+    matrix::emotes::Emoji {
+        id: emoji.id.to_string(),
+        name: emoji.name.clone(),
+        animated: emoji.animated,
+    }
+}
+
+/// A Discord user and the id of their DMs with the bot, for starting a bridged DM from Matrix.
+pub async fn dm_channel(discord_id: u64) -> Result<(User, u64)> {
+    let ctx = get_context().await;
+    let user = ctx.http.get_user(discord_id).await?;
+    let channel = user.create_dm_channel(&ctx.http).await?;
+    Ok((author_to_user(user).await, channel.id.0))
+}
+
+/// Fetches up to `limit` of the latest messages in a channel, stopping early at
+/// the first message `stop` returns true for. Returned oldest first.
+async fn fetch_history(
+    ctx: &Context,
+    channel_id: u64,
+    limit: usize,
+    stop: impl Fn(&Message) -> bool,
+) -> Result<Vec<Message>> {
+    let mut history: Vec<Message> = Vec::new();
+    let mut before: Option<MessageId> = None;
+    'pages: while history.len() < limit {
+        let page_limit = (limit - history.len()).min(100) as u64;
+        let page = ChannelId(channel_id)
+            .messages(&ctx.http, |b| {
+                if let Some(before) = before {
+                    b.before(before);
+                }
+                b.limit(page_limit)
+            })
+            .await?;
+        let page_len = page.len() as u64;
+
+        for msg in page {
+            if stop(&msg) {
+                break 'pages;
+            }
+            before = Some(msg.id);
+            history.push(msg);
+        }
+
+        if page_len < page_limit {
+            break;
+        }
+    }
+
+    history.sort_by_key(|msg| msg.id);
+    Ok(history)
+}
+
+/// What a Discord reaction is on Matrix. Custom emoji are reacted with as
+/// images, or their name if they can't be uploaded.
+async fn reaction_key(reaction_type: &ReactionType) -> Option<String> {
+    match reaction_type {
+        ReactionType::Unicode(emoji) => Some(emoji.clone()),
+        ReactionType::Custom { animated, id, name } => {
+            let name = name.clone().unwrap_or_default();
+            let emoji = matrix::emotes::Emoji {
+                id: id.to_string(),
+                name: name.clone(),
+                animated: *animated,
+            };
+            match matrix::emotes::emoji_mxc(&emoji).await {
+                Some(mxc_uri) => Some(mxc_uri.to_string()),
+                None => Some(format!(":{}:", name)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Relays a Discord user's reaction, saving it so it can be removed again.
+async fn relay_reaction(
+    user: User,
+    reacted: chat_service::Message,
+    key: String,
+    timestamp: Option<i64>,
+) {
+    let user_id = user.id.clone();
+    if let Some(relayed) =
+        matrix::relay::relay_reaction(user, reacted.clone(), key.clone(), timestamp).await
+    {
+        chat_service::save_reaction(&reacted, &user_id, &key, &relayed);
+    }
+}
+
+/// Relays the last `backfill` messages of a channel the first time it's bridged,
+/// including the reactions on them.
+async fn backfill_channel(ctx: &Context, room: &Entry) -> Result<()> {
+    let backfilled_key = format!("discord_backfilled_{}", room.discord);
+    if room.backfill == 0 || chat_service::get_state(&backfilled_key).is_some() {
+        return Ok(());
+    }
+    // The channel was bridged before backfilling was turned on
+    if last_relayed_message(room.discord).is_some() {
+        chat_service::set_state(&backfilled_key, "true");
+        return Ok(());
+    }
+
+    let history = fetch_history(ctx, room.discord, room.backfill, |_| false).await?;
+    println!("Backfilling {} messages in {}", history.len(), room.discord);
+
+    for msg in history {
+        relay_discord_message(msg.clone()).await;
+
+        for reaction in msg.reactions.iter() {
+            let Some(key) = reaction_key(&reaction.reaction_type).await else {
+                continue;
+            };
+            let users = msg
+                .reaction_users(
+                    &ctx.http,
+                    reaction.reaction_type.clone(),
+                    Some(100),
+                    None::<UserId>,
+                )
+                .await?;
+            for user in users {
+                if user.bot {
+                    continue;
+                }
+                relay_reaction(
+                    author_to_user(user).await,
+                    message_to_relayed_message(msg.clone(), room.discord_guild.to_string()),
+                    key.clone(),
+                    Some(message_id_timestamp(msg.id)),
+                )
+                .await;
+            }
+        }
+    }
+
+    chat_service::set_state(&backfilled_key, "true");
+    Ok(())
+}
+
+/// Why catching up stops paging back at a message.
+#[derive(Debug, PartialEq)]
+pub enum CatchUpStop {
+    /// It was already relayed
+    Relayed,
+    /// It's older than `catch_up_max_age` but wasn't relayed, so it's skipped
+    TooOld,
+}
+
+/// Whether catching up stops at a message, given the last relayed message
+/// and the oldest time messages are caught up from.
+pub fn catch_up_stop(id: MessageId, last: MessageId, oldest: i64) -> Option<CatchUpStop> {
+    if id <= last {
+        Some(CatchUpStop::Relayed)
+    } else if message_id_timestamp(id) < oldest {
+        Some(CatchUpStop::TooOld)
+    } else {
+        None
+    }
+}
+
+/// Drops the oldest of messages sorted oldest first past `limit`, returning
+/// whether any were.
+pub fn truncate_oldest<T>(messages: &mut Vec<T>, limit: usize) -> bool {
+    let over = messages.len().saturating_sub(limit);
+    messages.drain(..over);
+    over > 0
+}
+
+/// Relays messages that were sent while the relay was offline, paging back to
+/// the last relayed message. If there were more than `catch_up_limit` or some
+/// were older than `catch_up_max_age`, the room is told the rest were skipped.
+async fn catch_up_channel(ctx: &Context, room: &Entry) -> Result<()> {
+    // Nothing has been relayed from this channel yet, so there's nothing to catch up on
+    let Some(last) = last_relayed_message(room.discord) else {
+        return Ok(());
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis() as i64;
+    let oldest = now - (CONFIG.catch_up_max_age * 1000) as i64;
+
+    // One more than the limit is fetched, to tell if there were more than it
+    let too_old = std::cell::Cell::new(false);
+    let stop = |msg: &Message| {
+        let stop = catch_up_stop(msg.id, last, oldest);
+        if stop == Some(CatchUpStop::TooOld) {
+            too_old.set(true);
+        }
+        stop.is_some()
+    };
+    let mut missed = fetch_history(ctx, room.discord, CONFIG.catch_up_limit + 1, stop).await?;
+    let over_limit = truncate_oldest(&mut missed, CONFIG.catch_up_limit);
+
+    if over_limit || too_old.get() {
+        println!("Skipped catching up on older messages in {}", room.discord);
+        matrix::relay::send_notice(
+            &room.matrix,
+            format!(
+                "Some messages sent on Discord while the bridge was offline weren't relayed, they're after https://discord.com/channels/{}/{}/{}",
+                room.discord_guild, room.discord, last
+            ),
+        )
+        .await;
+    }
+    if missed.len() > 0 {
+        println!(
+            "Catching up on {} messages in {}",
+            missed.len(),
+            room.discord
+        );
+    }
+
+    for msg in missed {
+        relay_discord_message(msg).await;
+    }
+
+    Ok(())
+}
+
+fn member_to_user(member: &Member) -> User {
+    User {
+        source: "discord".to_string(),
+        id: member.user.id.to_string(),
+        ping: format!("<@{}>", member.user.id),
+        tag: member.user.tag(),
+        display: member.display_name().to_string(),
+        avatar: member.user.avatar_url(),
+    }
+}
+
+/// Matrix power level for a member, the highest level of the rules matching
+/// their roles or their permissions in the channel.
+pub fn member_power_level(
+    rules: &[PowerLevelRule],
+    roles: &[RoleId],
+    permissions: Permissions,
+) -> i64 {
+    // e.g "Manage Messages" -> "MANAGE_MESSAGES"
+    let permission_names: Vec<String> = permissions
+        .get_permission_names()
+        .iter()
+        .map(|name| name.to_uppercase().replace(' ', "_"))
+        .collect();
+
+    rules
+        .iter()
+        .filter(|rule| {
+            rule.role
+                .map_or(false, |role| roles.contains(&RoleId(role)))
+                || rule.permission.as_ref().map_or(false, |permission| {
+                    permission_names.contains(&permission.to_uppercase())
+                })
+        })
+        .map(|rule| rule.level)
+        .max()
+        .unwrap_or(0)
+}
+
+/// When a member's timeout ends, None if they aren't timed out.
+fn timeout_end(member: &Member) -> Option<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs() as i64;
+    member
+        .communication_disabled_until
+        .map(|until| until.unix_timestamp())
+        .filter(|until| *until > now)
+}
+
+/// Discord doesn't send an event when a timeout ends, so the member is synced
+/// again once it has to unmute their puppet. Any earlier task for the member
+/// is replaced, as the timeout may have been changed or removed.
+fn schedule_timeout_end(ctx: &Context, member: &Member) {
+    let key = (member.guild_id.0, member.user.id.0);
+    if let Some(task) = TIMEOUT_TASKS.lock().remove(&key) {
+        task.abort();
+    }
+    let Some(until) = timeout_end(member) else {
+        return;
+    };
+    let ctx = ctx.clone();
+    let (guild_id, user_id) = (member.guild_id, member.user.id);
+    let task = tokio::spawn(async move {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(until);
+        tokio::time::sleep(Duration::from_secs((until - now).max(0) as u64 + 1)).await;
+        TIMEOUT_TASKS.lock().remove(&key);
+        if let Some(member) = ctx.cache.member(guild_id, user_id) {
+            sync_member(&ctx, &member).await;
+        }
+    });
+    TIMEOUT_TASKS.lock().insert(key, task);
+}
+
+/// Puts a member's puppet in the bridged rooms of channels they can see, and
+/// takes it out of the ones they can't.
+async fn sync_member(ctx: &Context, member: &Member) {
+    if member.user.bot || privacy::is_opted_out("discord", &member.user.id.to_string()) {
+        return;
+    }
+    let user = member_to_user(member);
+
+    // Timeouts mute the puppet, which is only undone if the bridge muted it
+    let muted_key = format!("discord_muted_{}_{}", member.guild_id, user.id);
+    let was_muted = chat_service::get_state(&muted_key).as_deref() == Some("true");
+    let timed_out = timeout_end(member).is_some();
+    if timed_out != was_muted {
+        chat_service::set_state(&muted_key, &timed_out.to_string());
+    }
+
+    for room in CONFIG.room.iter() {
+        if room.discord_guild != member.guild_id.0 {
+            continue;
+        }
+        if chat_service::is_banned("discord", &user.id, &room.matrix) {
+            continue;
+        }
+
+        let permissions = ctx
+            .cache
+            .guild_channel(room.discord)
+            .and_then(|channel| {
+                channel
+                    .permissions_for_user(&ctx.cache, member.user.id)
+                    .ok()
+            })
+            .unwrap_or_else(Permissions::empty);
+        if permissions.view_channel() {
+            matrix::relay::join_puppet(user.clone(), &room.matrix, room.member_notices).await;
+            // Muted puppets keep their lowered level until the timeout ends
+            if !CONFIG.power_level.is_empty() && !timed_out {
+                let level = member_power_level(&CONFIG.power_level, &member.roles, permissions);
+                matrix::relay::set_puppet_power_level(user.id.clone(), &room.matrix, level).await;
+            }
+            if timed_out != was_muted {
+                let action = if timed_out { "timeout" } else { "timeout end" };
+                chat_service::log_moderation("discord", action, &user.id, &room.matrix, None);
+            }
+            if timed_out || was_muted {
+                matrix::relay::mute_puppet(user.id.clone(), &room.matrix, timed_out).await;
+            }
+        } else {
+            matrix::relay::leave_puppet(user.clone(), &room.matrix, room.member_notices).await;
+        }
+    }
+}
+
+/// Schedules unmuting members whose timeouts are still going after a restart,
+/// and unmutes those whose timeouts ended while the bridge was offline.
+async fn resume_timeouts(ctx: &Context, guild: &Guild) {
+    for member in guild.members.values() {
+        if timeout_end(member).is_some() {
+            schedule_timeout_end(ctx, member);
+            continue;
+        }
+        let muted_key = format!("discord_muted_{}_{}", member.guild_id, member.user.id);
+        if chat_service::get_state(&muted_key).as_deref() == Some("true") {
+            sync_member(ctx, member).await;
+        }
+    }
+}
+
+/// Sets the power levels of members whose puppets are already in bridged
+/// rooms, as their roles may have changed while the bridge was offline.
+async fn sync_power_levels(ctx: &Context, guild: &Guild) {
+    if CONFIG.power_level.is_empty() {
+        return;
+    }
+    for room in CONFIG
+        .room
+        .iter()
+        .filter(|room| room.discord_guild == guild.id.0)
+    {
+        let Some(channel) = ctx.cache.guild_channel(room.discord) else {
+            continue;
+        };
+        let mut levels = Vec::new();
+        for member in guild.members.values() {
+            // Muted puppets keep their lowered level until the timeout ends
+            if member.user.bot || timeout_end(member).is_some() {
+                continue;
+            }
+            let Some(user_id) = matrix::puppet::user_id(&member.user.id.to_string()) else {
+                continue;
+            };
+            if !matrix::puppet::is_joined(user_id.as_str(), &room.matrix) {
+                continue;
+            }
+            let permissions = channel
+                .permissions_for_user(&ctx.cache, member.user.id)
+                .unwrap_or_else(|_| Permissions::empty());
+            let level = member_power_level(&CONFIG.power_level, &member.roles, permissions);
+            levels.push((user_id, level));
+        }
+        if !levels.is_empty() {
+            matrix::relay::set_puppet_power_levels(&room.matrix, levels).await;
+        }
+    }
+}
+
+/// A linked Discord account as it appears in a guild, for showing a Matrix
+/// user's messages with their Discord name and avatar.
+pub async fn linked_user(discord_id: &str, guild_id: u64) -> Option<User> {
+    let discord_id = UserId(discord_id.parse::<u64>().ok()?);
+    let ctx = get_context().await;
+    if let Some(member) = ctx.cache.member(GuildId(guild_id), discord_id) {
+        return Some(member_to_user(&member));
+    }
+    let user = ctx.http.get_user(discord_id.0).await.ok()?;
+    Some(author_to_user(user).await)
+}
+
 async fn author_to_user(author: serenity::model::prelude::User) -> User {
     return User {
         source: "discord".to_string(), // Source, e.g matrix, discord
@@ -38,7 +673,7 @@ async fn author_to_user(author: serenity::model::prelude::User) -> User {
         ping: format!("<@{}>", author.id.to_string()), // Used to mention user
         tag: format!("{}", author.tag()), // Used to tag (kinda)
         display: author.name.to_owned(), // Display Name
-        avatar: author.avatar,
+        avatar: author.avatar_url(),
     };
 }
 
@@ -58,42 +693,114 @@ async fn message_to_full_message(msg: Message) -> chat_service::FullMessage {
         user.display = nick.unwrap().to_owned();
     }
 
-    let relay_msg = message_to_relayed_message(msg.clone(), msg.guild_id.unwrap().to_string());
+    // DMs aren't in a guild
+    let guild_id = msg.guild_id.map(|id| id.to_string()).unwrap_or_default();
+    let relay_msg = message_to_relayed_message(msg.clone(), guild_id.clone());
 
     let mut reply: Option<Box<chat_service::Message>> = None;
+    let mut reply_preview: Option<ReplyPreview> = None;
     if msg.referenced_message.is_some() {
         //TODO: This may be recursive...
-        let replyed_msg = *(msg.referenced_message.unwrap());
-        reply = Some(Box::new(message_to_relayed_message(
-            replyed_msg,
-            msg.guild_id.unwrap().to_string(),
-        )));
+        let replyed_msg = *(msg.referenced_message.clone().unwrap());
+
+        let mut excerpt = chat_service::excerpt(&replyed_msg.content);
+        if excerpt == "" {
+            if let Some(attachment) = replyed_msg.attachments.first() {
+                excerpt = format!("📎 {}", attachment.filename);
+            }
+        }
+        reply_preview = Some(ReplyPreview {
+            user: author_to_user(replyed_msg.author.clone()).await,
+            excerpt: excerpt,
+            mention: msg
+                .mentions
+                .iter()
+                .any(|user| user.id == replyed_msg.author.id),
+        });
+
+        reply = Some(Box::new(message_to_relayed_message(replyed_msg, guild_id)));
     }
 
+    let mut content = msg.content.clone();
+    // Link previews repeat what's in the content, so only bots' embeds are kept
+    for embed in msg
+        .embeds
+        .iter()
+        .filter(|embed| embed.kind.as_deref() == Some("rich"))
+    {
+        let embed = embed_to_markdown(embed);
+        if embed.is_empty() {
+            continue;
+        }
+        if content != "" {
+            content.push('\n');
+        }
+        content.push_str(&embed);
+    }
+    let attachments = msg
+        .attachments
+        .iter()
+        .map(|attachment| Attachment {
+            name: attachment.filename.clone(),
+            url: attachment.url.clone(),
+            content_type: attachment.content_type.clone(),
+            size: attachment.size,
+        })
+        .collect();
+
+    // Discord only allows one sticker per message
+    let sticker = msg.sticker_items.first().map(|item| Sticker {
+        name: item.name.clone(),
+        // GIF stickers are newer than serenity's sticker formats
+        url: item.image_url().unwrap_or(format!(
+            "https://media.discordapp.net/stickers/{}.gif",
+            item.id
+        )),
+        lottie: item.format_type == StickerFormatType::Lottie,
+    });
+
     let full_msg = FullMessage {
         user: user,
         message: relay_msg,
-        content: msg.content.clone(),
+        content: content,
         reply: reply,
+        reply_preview: reply_preview,
+        timestamp: Some(message_id_timestamp(msg.id)),
+        sticker,
+        attachments,
     };
 
     return full_msg;
 }
 
-pub async fn relayed_message_to_message(msg: chat_service::Message) -> Option<Message> {
-    // This may or may not work...
-    let ctx = (*CONTEXT.lock()).clone().unwrap();
-    let guild_id = GuildId(msg.server_id.parse::<u64>().unwrap());
-    let channel_id = ChannelId(msg.room_id.parse::<u64>().unwrap());
-    let channels = guild_id.channels(ctx.http.clone()).await;
-    let channel = channels.as_ref().unwrap().get(&channel_id).unwrap();
-
-    let message_id = MessageId(msg.id.parse::<u64>().unwrap());
-    let out_msg = channel.message(ctx.http.clone(), message_id).await;
-    if out_msg.is_ok() {
-        return Some(out_msg.unwrap());
+/// The title, description and fields of an embed, as a quote.
+pub fn embed_to_markdown(embed: &Embed) -> String {
+    let mut lines = Vec::new();
+    match (&embed.title, &embed.url) {
+        (Some(title), Some(url)) => lines.push(format!("**[{title}]({url})**")),
+        (Some(title), None) => lines.push(format!("**{title}**")),
+        _ => {}
     }
-    return None;
+    if let Some(description) = &embed.description {
+        lines.extend(description.lines().map(|line| line.to_owned()));
+    }
+    for field in embed.fields.iter() {
+        lines.push(format!("**{}**: {}", field.name, field.value));
+    }
+    lines
+        .iter()
+        .map(|line| format!("> {line}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Fetches a message the bridge sent to Discord. DMs have no guild, so
+/// messages are looked up by their channel alone.
+pub async fn relayed_message_to_message(msg: chat_service::Message) -> Option<Message> {
+    let ctx = (*CONTEXT.lock()).clone()?;
+    let channel_id = ChannelId(msg.room_id.parse::<u64>().ok()?);
+    let message_id = MessageId(msg.id.parse::<u64>().ok()?);
+    channel_id.message(&ctx.http, message_id).await.ok()
 }
 
 #[async_trait]
@@ -105,20 +812,12 @@ impl EventHandler for Handler {
     // events can be dispatched simultaneously.
     async fn message(&self, ctx: Context, msg: Message) {
         println!("{} {} {}", msg.content, msg.id, msg.author.bot);
-        if msg.author.bot {
-            return;
-        }
-
-        let room = CONFIG
-            .room
-            .iter()
-            .find(|room| room.discord == msg.channel_id.0);
-        if room.is_some() {
-            let relay_msg = message_to_full_message(msg).await;
-            let relayed = matrix::relay::relay_message(relay_msg.clone()).await;
 
-            chat_service::create_message(relay_msg.message, relayed);
+        if msg.guild_id.is_none() {
+            relay_dm(msg).await;
+            return;
         }
+        relay_live_message(msg).await;
     }
 
     async fn message_delete(
@@ -130,7 +829,7 @@ impl EventHandler for Handler {
     ) {
         let msg = chat_service::Message {
             service: "discord".to_owned(),
-            server_id: guild_id.unwrap().to_string(),
+            server_id: guild_id.map(|id| id.to_string()).unwrap_or_default(),
             room_id: channel_id.to_string(),
             id: deleted_message_id.to_string(),
         };
@@ -139,29 +838,283 @@ impl EventHandler for Handler {
         chat_service::delete_message(msg.clone());
     }
 
+    async fn reaction_add(&self, ctx: Context, add_reaction: Reaction) {
+        let Some(room) = CONFIG
+            .room
+            .iter()
+            .find(|room| room.discord == add_reaction.channel_id.0)
+        else {
+            return;
+        };
+        // Reactions relayed from Matrix are the bot's
+        let user = match add_reaction.user(&ctx).await {
+            Ok(user) if !user.bot => user,
+            _ => return,
+        };
+        if privacy::is_opted_out("discord", &user.id.to_string())
+            || chat_service::is_banned("discord", &user.id.to_string(), &room.matrix)
+        {
+            return;
+        }
+        let Some(key) = reaction_key(&add_reaction.emoji).await else {
+            return;
+        };
+
+        let reacted = chat_service::Message {
+            service: "discord".to_owned(),
+            server_id: room.discord_guild.to_string(),
+            room_id: add_reaction.channel_id.to_string(),
+            id: add_reaction.message_id.to_string(),
+        };
+        relay_reaction(author_to_user(user).await, reacted, key, None).await;
+    }
+
+    async fn reaction_remove(&self, _ctx: Context, removed_reaction: Reaction) {
+        let Some(user_id) = removed_reaction.user_id else {
+            return;
+        };
+        let Some(key) = reaction_key(&removed_reaction.emoji).await else {
+            return;
+        };
+        let reacted = chat_service::Message {
+            service: "discord".to_owned(),
+            server_id: removed_reaction
+                .guild_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            room_id: removed_reaction.channel_id.to_string(),
+            id: removed_reaction.message_id.to_string(),
+        };
+        if let Some(relayed) = chat_service::take_reaction(&reacted, &user_id.to_string(), &key) {
+            matrix::relay::delete_event(&relayed).await;
+        }
+    }
+
     async fn message_update(
         &self,
-        _ctx: Context,
+        ctx: Context,
         old_if_available: Option<Message>,
         new: Option<Message>,
         event: MessageUpdateEvent,
     ) {
+        // Updates without content are e.g embeds being added to the message
+        let (Some(content), Some(author)) = (event.content, event.author) else {
+            return;
+        };
         let relay_msg = chat_service::Message {
             service: "discord".to_owned(),
             id: event.id.to_string(),
             room_id: event.channel_id.to_string(),
-            server_id: event.guild_id.unwrap().to_string(),
+            server_id: event.guild_id.map(|id| id.to_string()).unwrap_or_default(),
         };
 
         let relay_msg = chat_service::FullMessage {
-            content: event.content.unwrap().clone(),
-            user: author_to_user(event.author.unwrap()).await,
+            content,
+            user: author_to_user(author).await,
             message: relay_msg,
             reply: None,
+            reply_preview: None,
+            timestamp: None,
+            sticker: None,
+            attachments: vec![],
         };
+        if !filter::screen(&relay_msg, 0, true).await {
+            // Its copy on Matrix goes along with it
+            if CONFIG.filter.action == FilterAction::Redact {
+                if let Err(err) = event.channel_id.delete_message(&ctx.http, event.id).await {
+                    println!("Failed to delete filtered message: {}", err);
+                }
+            }
+            return;
+        }
         matrix::relay::edit_message(relay_msg).await;
     }
 
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let Interaction::ApplicationCommand(command) = interaction else {
+            return;
+        };
+        let user_id = command.user.id.to_string();
+
+        let reply = match command.data.name.as_str() {
+            "link" => {
+                let code = command
+                    .data
+                    .options
+                    .iter()
+                    .find(|option| option.name == "code")
+                    .and_then(|option| option.value.as_ref())
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default();
+                match link::redeem_code(code, &user_id) {
+                    Some(matrix_id) => format!("Linked your account to {matrix_id}"),
+                    None => "That code is invalid or has expired, send !link to the bridge bot on Matrix for a new one".to_owned(),
+                }
+            }
+            "optout" => {
+                privacy::opt_out("discord", &user_id);
+                "Your messages won't be bridged to Matrix anymore".to_owned()
+            }
+            "optin" => match privacy::opt_in("discord", &user_id) {
+                true => "Your messages will be bridged to Matrix again".to_owned(),
+                false => "You haven't opted out".to_owned(),
+            },
+            "forgetme" => "Removing your bridged messages from Matrix and forgetting you. You'll be opted out, use /optin to have your messages bridged again".to_owned(),
+            _ => return,
+        };
+
+        // Only the user using the command sees the reply
+        let res = command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|d| d.content(reply).ephemeral(true))
+            })
+            .await;
+        if let Err(err) = res {
+            println!("Failed to reply to command: {}", err);
+        }
+
+        // Done after replying, as Discord only waits a few seconds for the reply
+        match command.data.name.as_str() {
+            "optout" => {
+                let user = author_to_user(command.user.clone()).await;
+                for room in CONFIG.room.iter() {
+                    matrix::relay::leave_puppet(user.clone(), &room.matrix, false).await;
+                }
+            }
+            "forgetme" => {
+                privacy::forget("discord", &user_id).await;
+                if let Some(notice) = privacy::unattributed_notice("discord") {
+                    let res = command
+                        .create_followup_message(&ctx.http, |f| f.content(notice).ephemeral(true))
+                        .await;
+                    if let Err(err) = res {
+                        println!("Failed to reply to command: {}", err);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        sync_member(&ctx, &new_member).await;
+    }
+
+    async fn guild_member_update(&self, ctx: Context, _old: Option<Member>, new: Member) {
+        // Covers nickname and avatar changes, role changes that change which
+        // channels the member can see and timeouts
+        sync_member(&ctx, &new).await;
+        schedule_timeout_end(&ctx, &new);
+    }
+
+    async fn guild_ban_addition(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        banned_user: serenity::model::prelude::User,
+    ) {
+        let user = author_to_user(banned_user).await;
+        for room in CONFIG.room.iter() {
+            if room.discord_guild != guild_id.0 {
+                continue;
+            }
+            chat_service::set_banned("discord", &user.id, &room.matrix, true);
+            chat_service::log_moderation("discord", "ban", &user.id, &room.matrix, None);
+            matrix::relay::ban_puppet(
+                user.id.clone(),
+                &room.matrix,
+                true,
+                Some("Banned on Discord"),
+            )
+            .await;
+        }
+    }
+
+    async fn guild_ban_removal(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        unbanned_user: serenity::model::prelude::User,
+    ) {
+        let user = author_to_user(unbanned_user).await;
+        for room in CONFIG.room.iter() {
+            if room.discord_guild != guild_id.0 {
+                continue;
+            }
+            chat_service::set_banned("discord", &user.id, &room.matrix, false);
+            chat_service::log_moderation("discord", "unban", &user.id, &room.matrix, None);
+            matrix::relay::ban_puppet(
+                user.id.clone(),
+                &room.matrix,
+                false,
+                Some("Unbanned on Discord"),
+            )
+            .await;
+        }
+    }
+
+    async fn guild_member_removal(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        user: serenity::model::prelude::User,
+        _member: Option<Member>,
+    ) {
+        let user = author_to_user(user).await;
+        for room in CONFIG.room.iter() {
+            if room.discord_guild == guild_id.0 {
+                matrix::relay::leave_puppet(user.clone(), &room.matrix, room.member_notices).await;
+            }
+        }
+    }
+
+    async fn guild_role_update(&self, ctx: Context, _old: Option<Role>, new: Role) {
+        if CONFIG.power_level.is_empty() {
+            return;
+        }
+        let Some(guild) = ctx.cache.guild(new.guild_id) else {
+            return;
+        };
+
+        // The role's permissions may have changed, so members with it may need a different power level
+        for member in guild.members.values() {
+            if member.roles.contains(&new.id) {
+                sync_member(&ctx, member).await;
+            }
+        }
+    }
+
+    async fn channel_update(&self, _ctx: Context, old: Option<Channel>, new: Channel) {
+        let Channel::Guild(channel) = new else {
+            return;
+        };
+        let Some(room) = CONFIG.room.iter().find(|room| room.discord == channel.id.0) else {
+            return;
+        };
+        let old = match old {
+            Some(Channel::Guild(old)) => Some(old),
+            _ => None,
+        };
+
+        let name_changed = old.as_ref().map_or(true, |old| old.name != channel.name);
+        if room.name_to_matrix && name_changed {
+            // Don't echo back a name that was just set from Matrix
+            let echoed = matrix::relay::room_name(&room.matrix).map_or(false, |name| {
+                relay::channel_name_matches(&channel.name, &name)
+            });
+            if !echoed {
+                matrix::relay::set_room_name(&room.matrix, channel.name.clone()).await;
+            }
+        }
+
+        let topic_changed = old.as_ref().map_or(true, |old| old.topic != channel.topic);
+        if room.topic_to_matrix && topic_changed {
+            matrix::relay::set_room_topic(&room.matrix, channel.topic.clone().unwrap_or_default())
+                .await;
+        }
+    }
+
     // Set a handler to be called on the `ready` event. This is called when a
     // shard is booted, and a READY payload is sent by Discord. This payload
     // contains data like the current user's guild Ids, current user data,
@@ -171,23 +1124,87 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         *CONTEXT.lock() = Some(ctx.clone());
         println!("{} is connected!", ready.user.name);
+
+        let commands = Command::set_global_application_commands(&ctx.http, |commands| {
+            commands
+                .create_application_command(|c| {
+                    c.name("link")
+                        .description("Link your Discord account to your Matrix account")
+                        .create_option(|o| {
+                            o.name("code")
+                                .description("Code from sending !link to the bridge bot on Matrix")
+                                .kind(CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name("optout")
+                        .description("Stop your messages being bridged to Matrix")
+                })
+                .create_application_command(|c| {
+                    c.name("optin")
+                        .description("Have your messages bridged to Matrix again")
+                })
+                .create_application_command(|c| {
+                    c.name("forgetme").description(
+                        "Remove your bridged messages from Matrix and everything the bridge knows about you",
+                    )
+                })
+        })
+        .await;
+        if let Err(err) = commands {
+            println!("Failed to create commands: {}", err);
+        }
+
+        for room in CONFIG.room.iter() {
+            let lock = channel_lock(room.discord);
+            let _guard = lock.lock().await;
+            if let Err(err) = backfill_channel(&ctx, room).await {
+                println!("Failed to backfill {}: {}", room.discord, err);
+            }
+            if let Err(err) = catch_up_channel(&ctx, room).await {
+                println!("Failed to catch up on {}: {}", room.discord, err);
+            }
+            CAUGHT_UP.lock().insert(room.discord);
+            CAUGHT_UP_NOTIFY.notify_waiters();
+        }
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        let emojis = guild.emojis.values().map(to_emote).collect();
+        matrix::emotes::sync_packs(guild.id.0, emojis).await;
+        sync_power_levels(&ctx, &guild).await;
+        resume_timeouts(&ctx, &guild).await;
+    }
+
+    async fn guild_emojis_update(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        current_state: HashMap<EmojiId, Emoji>,
+    ) {
+        let emojis = current_state.values().map(to_emote).collect();
+        matrix::emotes::sync_packs(guild_id.0, emojis).await;
     }
 }
 
 pub async fn get_or_create_webhook_url(http: &Http, channel_id: u64) -> Result<String> {
-    let webhook_prefix = format!("https://discord.com/api/webhooks/{channel_id}/");
-
-    let database = DATABASE.lock();
     // get webhook url from database
-    let mut stmt = database.prepare("SELECT webhook_token FROM discord_channels WHERE id=:s")?;
-    let mut iter = stmt.query_map(&[(":s", &channel_id)], |row| {
-        Ok(row.get::<usize, String>(0)?)
-    })?;
-
-    let database_webhook_token = iter.next().map(|x| x.unwrap());
+    let database_webhook = {
+        let database = DATABASE.lock();
+        let mut stmt = database.prepare(
+            "SELECT webhook_id, webhook_token FROM discord_channels WHERE id=:s AND webhook_id IS NOT NULL",
+        )?;
+        let mut iter = stmt.query_map(&[(":s", &channel_id)], |row| {
+            Ok((row.get::<usize, u64>(0)?, row.get::<usize, String>(1)?))
+        })?;
+        iter.next().map(|x| x.unwrap())
+    };
     // it's already in the database :)
-    if let Some(webhook_token) = database_webhook_token {
-        return Ok(format!("{webhook_prefix}{webhook_token}"));
+    if let Some((webhook_id, webhook_token)) = database_webhook {
+        return Ok(format!(
+            "https://discord.com/api/webhooks/{webhook_id}/{webhook_token}"
+        ));
     }
 
     // ok the bot's gonna have to make the webhook
@@ -203,13 +1220,50 @@ pub async fn get_or_create_webhook_url(http: &Http, channel_id: u64) -> Result<S
         bail!("Webhook token is None")
     };
 
-    // add it to the database
-    database.execute(
-        "INSERT OR IGNORE INTO discord_channels (id, webhook_token) VALUES (?, ?)",
-        (channel_id, &token),
+    // add it to the database, replacing any webhook saved without its id
+    DATABASE.lock().execute(
+        "INSERT OR REPLACE INTO discord_channels (id, webhook_id, webhook_token) VALUES (?, ?, ?)",
+        (channel_id, webhook.id.0, &token),
     )?;
 
-    Ok(format!("{webhook_prefix}{token}"))
+    Ok(format!(
+        "https://discord.com/api/webhooks/{}/{token}",
+        webhook.id
+    ))
+}
+
+/// Whether a webhook is one the bridge uses to relay messages to Discord.
+fn is_bridge_webhook(webhook_id: WebhookId) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM discord_channels WHERE webhook_id=?)",
+            [webhook_id.0],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+/// Messages the bridge sent itself are never relayed back, other bots are
+/// relayed unless they're filtered out by the room's bot allow/deny lists.
+fn should_relay(ctx: &Context, room: &Entry, msg: &Message) -> bool {
+    if let Some(webhook_id) = msg.webhook_id {
+        if is_bridge_webhook(webhook_id) {
+            return false;
+        }
+    }
+    if msg.author.id == ctx.cache.current_user_id() {
+        return false;
+    }
+    !msg.author.bot || bot_allowed(room, msg.author.id.0)
+}
+
+/// Whether a bot's messages are relayed in a room, by its allow and deny lists.
+pub fn bot_allowed(room: &Entry, bot_id: u64) -> bool {
+    if room.bot_deny.contains(&bot_id) {
+        return false;
+    }
+    room.bot_allow.is_empty() || room.bot_allow.contains(&bot_id)
 }
 
 pub async fn start_bot() {
@@ -217,7 +1271,12 @@ pub async fn start_bot() {
     //let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
     let token = CONFIG.discord_token.clone();
     // Set gateway intents, which decides what events the bot will be notified about
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MEMBERS
+        | GatewayIntents::GUILD_BANS
+        | GatewayIntents::GUILD_EMOJIS_AND_STICKERS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
 
@@ -0,0 +1,123 @@
+use regex::Regex;
+use serenity::model::prelude::{Emoji, GuildId, ReactionType};
+
+use crate::{matrix, DATABASE};
+
+use super::bot::get_context;
+
+lazy_static! {
+    static ref EMOTICON: Regex = Regex::new(r"<img\b[^>]*\bdata-mx-emoticon\b[^>]*>").unwrap();
+    static ref ATTRIBUTE: Regex = Regex::new(r#"\b(src|alt|title)="([^"]*)""#).unwrap();
+}
+
+/// The guild emoji most like a Matrix emote. That's the emoji the emote was
+/// uploaded from, or else the closest by name.
+async fn closest_emoji(guild_id: u64, mxc_uri: &str, name: &str) -> Option<Emoji> {
+    let ctx = get_context().await;
+    let emojis: Vec<Emoji> = ctx.cache.guild_field(GuildId(guild_id), |guild| {
+        guild.emojis.values().cloned().collect()
+    })?;
+
+    let uploaded_from: Option<String> = DATABASE
+        .lock()
+        .query_row(
+            "SELECT discord_id FROM emoji WHERE mxc_uri=?",
+            [mxc_uri],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(emoji) =
+        uploaded_from.and_then(|id| emojis.iter().find(|emoji| emoji.id.to_string() == id))
+    {
+        return Some(emoji.clone());
+    }
+
+    closest_by_name(&emojis, name).cloned()
+}
+
+/// The first emoji with a name, or else the same name in a different case.
+pub fn closest_by_name<'a>(emojis: &'a [Emoji], name: &str) -> Option<&'a Emoji> {
+    let name = name.trim_matches(':');
+    let lowercase = name.to_lowercase();
+    if name.is_empty() {
+        return None;
+    }
+    emojis.iter().find(|emoji| emoji.name == name).or_else(|| {
+        emojis
+            .iter()
+            .find(|emoji| emoji.name.to_lowercase() == lowercase)
+    })
+}
+
+/// The image and alt text of each emote in a Matrix message's HTML.
+pub fn emotes_in(formatted_body: &str) -> Vec<(String, String)> {
+    EMOTICON
+        .find_iter(formatted_body)
+        .filter_map(|tag| {
+            let mut src = None;
+            let mut alt = None;
+            for attribute in ATTRIBUTE.captures_iter(tag.as_str()) {
+                match &attribute[1] {
+                    "src" => src = Some(attribute[2].to_owned()),
+                    // Clients put either in the body
+                    _ if alt.is_none() => alt = Some(attribute[2].to_owned()),
+                    _ => {}
+                }
+            }
+            Some((src?, alt.filter(|alt| !alt.is_empty())?))
+        })
+        .collect()
+}
+
+/// Replaces the alt text of emotes in a message body, in order, each after
+/// where the last one was found.
+pub fn replace_emotes(body: &str, replacements: &[(String, String)]) -> String {
+    let mut content = body.to_owned();
+    let mut offset = 0;
+    for (alt, replacement) in replacements {
+        let Some(position) = content[offset..]
+            .find(alt.as_str())
+            .map(|found| found + offset)
+        else {
+            continue;
+        };
+        content.replace_range(position..position + alt.len(), replacement);
+        offset = position + replacement.len();
+    }
+    content
+}
+
+/// Replaces the alt text of Matrix emotes in a message body with the closest
+/// guild emoji, or a link to the emote's image if the guild has none like it.
+pub async fn emotes_to_discord(body: &str, formatted_body: Option<&str>, guild_id: u64) -> String {
+    let Some(formatted_body) = formatted_body else {
+        return body.to_owned();
+    };
+    let mut replacements = Vec::new();
+    for (mxc_uri, alt) in emotes_in(formatted_body) {
+        let replacement = match closest_emoji(guild_id, &mxc_uri, &alt).await {
+            Some(emoji) => emoji.to_string(),
+            None => match matrix::media::public_url(&mxc_uri) {
+                Some(url) => format!("[{alt}]({url})"),
+                None => continue,
+            },
+        };
+        replacements.push((alt, replacement));
+    }
+    replace_emotes(body, &replacements)
+}
+
+/// What to react with on Discord for a Matrix reaction, None if it's an emote
+/// the guild has nothing like.
+pub async fn reaction_to_discord(
+    key: &str,
+    shortcode: Option<&str>,
+    guild_id: u64,
+) -> Option<ReactionType> {
+    if !key.starts_with("mxc://") {
+        return Some(ReactionType::Unicode(key.to_owned()));
+    }
+    closest_emoji(guild_id, key, shortcode.unwrap_or_default())
+        .await
+        .map(ReactionType::from)
+}
@@ -1,2 +1,4 @@
 pub mod bot;
+pub mod emoji;
 pub mod relay;
+pub mod webhook;
@@ -1,12 +1,16 @@
-use crate::chat_service::{FullMessage, Message};
-use crate::{chat_service, CONFIG};
-use anyhow::Result;
-use reqwest;
+use crate::chat_service::{FullMessage, Message, Sticker};
+use crate::{chat_service, matrix, Entry, RelayMode, ReplyStyle, CONFIG};
+use anyhow::{bail, Result};
+use reqwest::multipart::{Form, Part};
 use serde::Deserialize;
 use serenity::http::Http;
-use std::collections::HashMap;
+use serenity::model::prelude::{
+    AttachmentType, Channel, ChannelId, GuildId, MessageId, ReactionType, UserId,
+};
+use serenity::prelude::Context;
 
 use super::bot::{get_or_create_webhook_url, relayed_message_to_message, CONTEXT};
+use super::webhook;
 
 #[derive(Debug, Deserialize, Clone)]
 struct WebhookResponse {
@@ -20,6 +24,28 @@ fn sanitize(message: &str) -> String {
     message.replace("@", &format!("@{ZERO_WIDTH_SPACE}"))
 }
 
+/// Adds a small note with the original send time to messages that reached
+/// Discord a while after they were sent.
+fn delayed_suffix(content: String, timestamp: Option<i64>) -> String {
+    let Some(timestamp) = timestamp else {
+        return content;
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(timestamp);
+    sent_time_suffix(content, timestamp, now, CONFIG.delayed_threshold)
+}
+
+/// The note added to a message sent at `timestamp` if it's at least
+/// `threshold` seconds late at `now`, both in milliseconds.
+pub fn sent_time_suffix(content: String, timestamp: i64, now: i64, threshold: u64) -> String {
+    if now - timestamp < (threshold * 1000) as i64 {
+        return content;
+    }
+    format!("{content}\n-# sent <t:{}:f>", timestamp / 1000)
+}
+
 pub async fn delete_message(message: Message) {
     let relayed_messages = chat_service::message_relays(message.clone());
     let http = (*CONTEXT.lock()).as_ref().unwrap().http.clone();
@@ -35,7 +61,11 @@ pub async fn delete_message(message: Message) {
     }
 
     let origin_message = chat_service::message_origin(message.clone());
-    if origin_message.is_some() && origin_message.clone().unwrap().clone().service == "discord" {
+    // The bot can't delete what a user sent it in DMs, which have no server
+    if origin_message.is_some()
+        && origin_message.clone().unwrap().clone().service == "discord"
+        && !origin_message.clone().unwrap().server_id.is_empty()
+    {
         let discord_msg = relayed_message_to_message(origin_message.unwrap()).await;
         if discord_msg.is_some() {
             discord_msg.unwrap().delete(http.clone()).await;
@@ -43,53 +73,315 @@ pub async fn delete_message(message: Message) {
     }
 }
 
+/// Reacts as the bot to the Discord copy of a message, or the original if it came from Discord.
+pub async fn react(message: Message, reaction: ReactionType) -> bool {
+    let http = (*CONTEXT.lock()).as_ref().unwrap().http.clone();
+    let Some(discord_msg) = chat_service::message_on_service(message, "discord") else {
+        return false;
+    };
+    let Some(discord_msg) = relayed_message_to_message(discord_msg).await else {
+        return false;
+    };
+    match discord_msg.react(&http, reaction).await {
+        Ok(_) => true,
+        Err(err) => {
+            println!("Failed to react: {}", err);
+            false
+        }
+    }
+}
+
+/// Removes the bot's reaction from the Discord copy of a message.
+pub async fn unreact(message: Message, reaction: ReactionType) {
+    let http = (*CONTEXT.lock()).as_ref().unwrap().http.clone();
+    let Some(discord_msg) = chat_service::message_on_service(message, "discord") else {
+        return;
+    };
+    let (Ok(channel_id), Ok(message_id)) = (
+        discord_msg.room_id.parse::<u64>(),
+        discord_msg.id.parse::<u64>(),
+    ) else {
+        return;
+    };
+    if let Err(err) = ChannelId(channel_id)
+        .delete_reaction(&http, MessageId(message_id), None, reaction)
+        .await
+    {
+        println!("Failed to remove reaction: {}", err);
+    }
+}
+
+/// Removes a Matrix reaction from Discord, unless someone else on
+/// Matrix reacted the same way, as they're all the bot's reaction there.
+/// Returns false if it isn't a relayed reaction.
+pub async fn remove_matrix_reaction(reaction: &Message) -> bool {
+    let Some((reacted, key, shared)) = chat_service::take_matrix_reaction(reaction) else {
+        return false;
+    };
+    if !shared {
+        if let Ok(reaction) = ReactionType::try_from(key) {
+            unreact(reacted, reaction).await;
+        }
+    }
+    true
+}
+
+/// Preview of a replied to message, shown as an embed
+pub struct ReplyEmbed {
+    pub author: String,
+    pub description: String,
+}
+
+/// Message content ready to be sent to Discord
+pub struct Rendered {
+    /// Already sanitized
+    pub content: String,
+    pub embed: Option<ReplyEmbed>,
+}
+
+/// A file attached to a message
+#[derive(Clone)]
+struct File {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// Downloads a sticker to attach it to a message.
+async fn sticker_file(sticker: &Sticker) -> Option<File> {
+    // Matrix media may need the bot's credentials to download
+    if sticker.url.starts_with("mxc://") {
+        let data = matrix::emotes::download(&sticker.url).await?;
+        let extension = image_extension(&data);
+        return Some(File {
+            name: format!("{}.{}", sticker.name, extension),
+            data,
+        });
+    }
+
+    let res = match reqwest::get(&sticker.url).await {
+        Ok(res) => res,
+        Err(err) => {
+            println!("Failed to download sticker {}: {}", sticker.url, err);
+            return None;
+        }
+    };
+    let extension = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .map(|content_type| content_type.subtype().to_string())
+        .unwrap_or("png".to_owned());
+    Some(File {
+        name: format!("{}.{}", sticker.name, extension),
+        data: res.bytes().await.ok()?.to_vec(),
+    })
+}
+
+/// The extension of an image, from its first bytes.
+pub fn image_extension(data: &[u8]) -> &'static str {
+    if data.starts_with(b"GIF8") {
+        "gif"
+    } else if data.len() > 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "webp"
+    } else {
+        "png"
+    }
+}
+
+/// Renders a message for Discord along with a preview of what it's replying to
+/// in the room's reply style. `native_reply` is set when the message will be a
+/// real Discord reply, which doesn't need a preview.
+pub fn render_message(room: &Entry, message: &FullMessage, native_reply: bool) -> Rendered {
+    let content = sanitize(&delayed_suffix(message.content.clone(), message.timestamp));
+    let preview = message.reply_preview.as_ref().filter(|_| !native_reply);
+    let Some(preview) = preview else {
+        return Rendered {
+            content,
+            embed: None,
+        };
+    };
+
+    // Replies to messages that were never bridged can't link to anything
+    let link = message
+        .reply
+        .as_ref()
+        .and_then(|reply| chat_service::message_on_service(*reply.clone(), "discord"))
+        .map(|msg| {
+            format!(
+                "https://discord.com/channels/{}/{}/{}",
+                msg.server_id, msg.room_id, msg.id
+            )
+        });
+    let mut excerpt = sanitize(&preview.excerpt);
+    if let Some(link) = link {
+        excerpt = format!("[{excerpt}]({link})");
+    }
+    let author = if preview.mention {
+        preview.user.ping.clone()
+    } else {
+        format!("**{}**", sanitize(&preview.user.display))
+    };
+
+    match room.reply_style {
+        ReplyStyle::Quote => Rendered {
+            content: format!("> {author} {excerpt}\n{content}"),
+            embed: None,
+        },
+        ReplyStyle::Compact => Rendered {
+            content: format!("↪ replying to {author}: {excerpt}\n{content}"),
+            embed: None,
+        },
+        ReplyStyle::Embed => Rendered {
+            // Embeds can't ping, so the ping goes in the content
+            content: match preview.mention {
+                true => format!("{author}\n{content}"),
+                false => content,
+            },
+            embed: Some(ReplyEmbed {
+                author: format!("↪ {}", preview.user.display),
+                description: excerpt,
+            }),
+        },
+    }
+}
+
+fn webhook_payload(
+    rendered: Rendered,
+    username: Option<String>,
+    avatar_url: Option<String>,
+) -> serde_json::Value {
+    let mut payload = serde_json::json!({ "content": rendered.content });
+    if let Some(username) = username {
+        payload["username"] = username.into();
+    }
+    if let Some(avatar_url) = avatar_url {
+        payload["avatar_url"] = avatar_url.into();
+    }
+    if let Some(embed) = rendered.embed {
+        payload["embeds"] = serde_json::json!([{
+            "author": { "name": embed.author },
+            "description": embed.description,
+        }]);
+    }
+    payload
+}
+
 async fn send_message_webhook(
     webhook_url: String,
-    message: String,
+    rendered: Rendered,
     username: Option<String>,
-) -> WebhookResponse {
-    let mut params = HashMap::new();
-    params.insert("content", sanitize(&message));
-    if username.is_some() {
-        params.insert("username", username.unwrap());
-    }
+    avatar_url: Option<String>,
+    file: Option<File>,
+) -> Result<WebhookResponse> {
+    let payload = webhook_payload(rendered, username, avatar_url);
 
     println!("Sending message to {webhook_url}");
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post(format!("{}?wait=1", webhook_url))
-        .form(&params)
-        .send()
-        .await
-        .expect("Should have sent message!")
-        .json::<WebhookResponse>()
-        .await
-        .expect("Should have parsed!");
-
-    return res;
+    webhook::send(&webhook_url, |client| {
+        let request = client.post(format!("{}?wait=1", webhook_url));
+        match &file {
+            Some(file) => {
+                request.multipart(Form::new().text("payload_json", payload.to_string()).part(
+                    "files[0]",
+                    Part::bytes(file.data.clone()).file_name(file.name.clone()),
+                ))
+            }
+            None => request.json(&payload),
+        }
+    })
+    .await
 }
 
 async fn edit_message_webhook(
     webhook: &str,
     message_id: String,
-    message: String,
-) -> WebhookResponse {
-    let mut params = HashMap::new();
-    params.insert("content", sanitize(&message));
-
-    let client = reqwest::Client::new();
-    let res = client
-        .patch(format!("{}/messages/{}", webhook, message_id))
-        .form(&params)
-        .send()
-        .await
-        .expect("Should have sent message!")
-        .json::<WebhookResponse>()
-        .await
-        .expect("Should have parsed!");
+    rendered: Rendered,
+) -> Result<WebhookResponse> {
+    let payload = webhook_payload(rendered, None, None);
+
+    webhook::send(webhook, |client| {
+        client
+            .patch(format!("{}/messages/{}", webhook, message_id))
+            .json(&payload)
+    })
+    .await
+}
 
-    return res;
+/// The Discord user a ping like `<@id>` mentions.
+pub fn ping_user_id(ping: &str) -> Option<UserId> {
+    let id = ping.strip_prefix("<@")?.strip_suffix('>')?;
+    id.trim_start_matches('!').parse::<u64>().ok().map(UserId)
+}
+
+fn bot_content(rendered: &Rendered, username: &str) -> String {
+    format!("**{}**: {}", sanitize(username), rendered.content)
+}
+
+async fn send_message_bot(
+    http: &Http,
+    channel_id: u64,
+    rendered: Rendered,
+    username: String,
+    reply: Option<MessageId>,
+    mention: Option<UserId>,
+    file: Option<File>,
+) -> Result<String> {
+    let content = bot_content(&rendered, &username);
+    let msg = ChannelId(channel_id)
+        .send_message(http, |m| {
+            m.content(content);
+            // Replies that can't be sent natively ping through the preview instead
+            m.allowed_mentions(|am| {
+                am.replied_user(mention.is_some())
+                    .users(mention.into_iter())
+            });
+            if let Some(reply) = reply {
+                m.reference_message((ChannelId(channel_id), reply));
+            }
+            if let Some(embed) = rendered.embed {
+                m.embed(|e| {
+                    e.author(|a| a.name(embed.author))
+                        .description(embed.description)
+                });
+            }
+            if let Some(file) = file {
+                m.add_file(AttachmentType::Bytes {
+                    data: file.data.into(),
+                    filename: file.name,
+                });
+            }
+            m
+        })
+        .await?;
+
+    Ok(msg.id.to_string())
+}
+
+/// The webhook to send messages in a room with, None if the room should use the
+/// bot instead, either because of its relay mode or because a webhook can't be used.
+async fn room_webhook_url(http: &Http, room: &Entry) -> Option<String> {
+    if room.relay_mode == RelayMode::Bot {
+        return None;
+    }
+
+    match get_or_create_webhook_url(http, room.discord).await {
+        Ok(webhook_url) => Some(webhook_url),
+        Err(err) => {
+            println!(
+                "Can't use a webhook in {}, sending as the bot instead: {}",
+                room.discord, err
+            );
+            None
+        }
+    }
+}
+
+/// The Discord message a Matrix reply should reference, if it was bridged.
+fn discord_reply_target(reply: &Option<Box<Message>>) -> Option<MessageId> {
+    let reply = reply.as_ref()?;
+    let discord_msg = chat_service::message_on_service(*reply.clone(), "discord")?;
+    discord_msg.id.parse::<u64>().ok().map(MessageId)
 }
 
 pub async fn relay_message(http: &Http, message: FullMessage) -> Result<Message> {
@@ -97,27 +389,122 @@ pub async fn relay_message(http: &Http, message: FullMessage) -> Result<Message>
         .room
         .iter()
         .find(|room| room.matrix == message.message.room_id);
-    let Some(room) = room else{
+    let Some(room) = room else {
         return Ok(message.message);
     };
 
-    let webhook_url = get_or_create_webhook_url(http, room.discord).await?;
-
-    let wh = send_message_webhook(
-        webhook_url,
-        message.content,
-        Some(format!("{} ({})", message.user.display, message.user.tag).to_owned()),
-    )
-    .await;
+    let username = format!("{} ({})", message.user.display, message.user.tag).to_owned();
+    let file = match &message.sticker {
+        Some(sticker) => sticker_file(sticker).await,
+        None => None,
+    };
+    let id = match room_webhook_url(http, room).await {
+        Some(webhook_url) => {
+            let rendered = render_message(room, &message, false);
+            send_message_webhook(
+                webhook_url,
+                rendered,
+                Some(username),
+                message.user.avatar.clone(),
+                file,
+            )
+            .await?
+            .id
+        }
+        None => {
+            // The bot can make real replies, so only needs a preview if it can't
+            let reply = discord_reply_target(&message.reply);
+            let rendered = render_message(room, &message, reply.is_some());
+            let mention = message
+                .reply_preview
+                .as_ref()
+                .filter(|preview| preview.mention)
+                .and_then(|preview| ping_user_id(&preview.user.ping));
+            send_message_bot(http, room.discord, rendered, username, reply, mention, file).await?
+        }
+    };
 
     Ok(Message {
         service: "discord".to_owned(),
         server_id: room.discord_guild.to_string(),
         room_id: room.discord.to_string(),
-        id: wh.id,
+        id: id,
     })
 }
 
+/// Relays a message from a bridged Matrix DM room to the Discord user's DMs with the bot.
+pub async fn relay_dm_message(
+    http: &Http,
+    channel_id: u64,
+    message: FullMessage,
+) -> Result<Message> {
+    let content = sanitize(&delayed_suffix(message.content.clone(), message.timestamp));
+    let msg = ChannelId(channel_id).say(http, content).await?;
+
+    Ok(Message {
+        service: "discord".to_owned(),
+        server_id: "".to_owned(),
+        room_id: channel_id.to_string(),
+        id: msg.id.to_string(),
+    })
+}
+
+/// Edits a Matrix message's copies in a Discord DM.
+pub async fn edit_dm_message(http: &Http, message: FullMessage) {
+    let content = sanitize(&message.content);
+    for msg in chat_service::message_relays(message.message) {
+        if msg.service != "discord" {
+            continue;
+        }
+        let (Ok(channel_id), Ok(message_id)) = (msg.room_id.parse::<u64>(), msg.id.parse::<u64>())
+        else {
+            continue;
+        };
+        if let Err(err) = ChannelId(channel_id)
+            .edit_message(http, MessageId(message_id), |m| m.content(&content))
+            .await
+        {
+            println!("Error editing DM: {}", err);
+        }
+    }
+}
+
+/// Posts a digest of recent Matrix history, split to fit Discord's message length limit.
+pub async fn send_digest(http: &Http, room: &Entry, lines: Vec<String>) -> Result<()> {
+    const MAX_LENGTH: usize = 2000;
+
+    let mut chunks: Vec<String> = vec!["**Recent messages from Matrix**".to_owned()];
+    for line in lines {
+        let line = sanitize(&line);
+        let last = chunks.last_mut().unwrap();
+        if last.len() + line.len() + 1 > MAX_LENGTH {
+            chunks.push(line.chars().take(MAX_LENGTH).collect());
+        } else {
+            last.push('\n');
+            last.push_str(&line);
+        }
+    }
+
+    let webhook_url = room_webhook_url(http, room).await;
+    for chunk in chunks {
+        let rendered = Rendered {
+            content: chunk,
+            embed: None,
+        };
+        let username = "Matrix History".to_owned();
+        match &webhook_url {
+            Some(webhook_url) => {
+                send_message_webhook(webhook_url.clone(), rendered, Some(username), None, None)
+                    .await?;
+            }
+            None => {
+                send_message_bot(http, room.discord, rendered, username, None, None, None).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
 pub async fn edit_message(http: &Http, message: FullMessage) {
     let room = CONFIG
         .room
@@ -128,18 +515,102 @@ pub async fn edit_message(http: &Http, message: FullMessage) {
         return;
     };
 
-    let webhook_url = match get_or_create_webhook_url(http, room.discord).await {
-        Ok(w) => w,
-        Err(err) => {
-            println!("Error getting webhook: {}", err);
-            return;
-        }
-    };
+    let webhook_url = room_webhook_url(http, room).await;
 
     let relayed_messages = chat_service::message_relays(message.clone().message);
     for msg in relayed_messages {
-        if msg.service == "discord" {
-            edit_message_webhook(&webhook_url, msg.id, message.clone().content).await;
+        if msg.service != "discord" {
+            continue;
+        }
+
+        // Messages sent as the bot (e.g if the webhook couldn't be used) can't be
+        // edited through the webhook, so fall back to editing them as the bot
+        if let Some(webhook_url) = &webhook_url {
+            let rendered = render_message(room, &message, false);
+            if edit_message_webhook(webhook_url, msg.id.clone(), rendered)
+                .await
+                .is_ok()
+            {
+                continue;
+            }
+        }
+
+        let Ok(message_id) = msg.id.parse::<u64>() else {
+            continue;
+        };
+        let native_reply = discord_reply_target(&message.reply).is_some();
+        let rendered = render_message(room, &message, native_reply);
+        let content = bot_content(
+            &rendered,
+            &format!("{} ({})", message.user.display, message.user.tag),
+        );
+        if let Err(err) = ChannelId(room.discord)
+            .edit_message(http, MessageId(message_id), |m| m.content(content))
+            .await
+        {
+            println!("Error editing message: {}", err);
         }
     }
 }
+
+/// Discord lowercases text channel names and replaces spaces with dashes, so a
+/// Matrix room name won't always come back from Discord exactly as it was sent.
+pub fn channel_name_matches(discord_name: &str, matrix_name: &str) -> bool {
+    discord_name == matrix_name || discord_name == matrix_name.to_lowercase().replace(' ', "-")
+}
+
+/// Bans a user from a room's guild, if the bot is allowed to.
+pub async fn ban_user(ctx: &Context, room: &Entry, user_id: u64, reason: &str) -> Result<()> {
+    let Some(channel) = ctx.cache.guild_channel(room.discord) else {
+        bail!("Channel {} isn't cached", room.discord);
+    };
+    let permissions = channel.permissions_for_user(&ctx.cache, ctx.cache.current_user_id())?;
+    if !permissions.ban_members() {
+        bail!(
+            "Missing permission to ban members in {}",
+            room.discord_guild
+        );
+    }
+
+    GuildId(room.discord_guild)
+        .ban_with_reason(&ctx.http, UserId(user_id), 0, reason)
+        .await?;
+    Ok(())
+}
+
+pub async fn edit_channel(
+    ctx: &Context,
+    channel_id: u64,
+    name: Option<String>,
+    topic: Option<String>,
+) -> Result<()> {
+    let Channel::Guild(channel) = ChannelId(channel_id).to_channel(ctx).await? else {
+        bail!("Channel {channel_id} isn't a guild channel");
+    };
+
+    let permissions = channel.permissions_for_user(&ctx.cache, ctx.cache.current_user_id())?;
+    if !permissions.manage_channels() {
+        bail!("Missing permission to manage channel {channel_id}");
+    }
+
+    let name = name.filter(|name| !channel_name_matches(&channel.name, name));
+    let topic = topic.filter(|topic| channel.topic.as_deref().unwrap_or_default() != topic);
+    if name.is_none() && topic.is_none() {
+        return Ok(());
+    }
+
+    channel
+        .id
+        .edit(&ctx.http, |c| {
+            if let Some(name) = name {
+                c.name(name);
+            }
+            if let Some(topic) = topic {
+                c.topic(topic);
+            }
+            c
+        })
+        .await?;
+
+    Ok(())
+}
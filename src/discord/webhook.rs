@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use reqwest::{header::HeaderMap, Client, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+/// How many times a request is retried after being rate limited before giving up
+const MAX_ATTEMPTS: usize = 5;
+
+struct Bucket {
+    remaining: u64,
+    reset_at: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct RateLimitResponse {
+    retry_after: f64,
+}
+
+lazy_static! {
+    static ref CLIENT: Client = Client::new();
+    static ref BUCKETS: parking_lot::Mutex<HashMap<String, Bucket>> =
+        parking_lot::Mutex::new(HashMap::new());
+    static ref QUEUES: parking_lot::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+/// Each webhook belongs to one channel, so queueing requests per webhook keeps
+/// messages in a channel in the order they were sent.
+fn queue(webhook_url: &str) -> Arc<tokio::sync::Mutex<()>> {
+    QUEUES
+        .lock()
+        .entry(webhook_url.to_owned())
+        .or_default()
+        .clone()
+}
+
+async fn wait_for_bucket(webhook_url: &str) {
+    let wait = {
+        let buckets = BUCKETS.lock();
+        match buckets.get(webhook_url) {
+            Some(bucket) if bucket.remaining == 0 => {
+                bucket.reset_at.saturating_duration_since(Instant::now())
+            }
+            _ => Duration::ZERO,
+        }
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+fn update_bucket(webhook_url: &str, headers: &HeaderMap) {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let remaining = header("x-ratelimit-remaining").and_then(|value| value.parse::<u64>().ok());
+    let reset_after = header("x-ratelimit-reset-after").and_then(|value| value.parse::<f64>().ok());
+
+    if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+        BUCKETS.lock().insert(
+            webhook_url.to_owned(),
+            Bucket {
+                remaining,
+                reset_at: Instant::now() + Duration::from_secs_f64(reset_after),
+            },
+        );
+    }
+}
+
+/// How long to wait before retrying, from the body of a rate limited response.
+pub fn retry_after(body: &str) -> Result<Duration> {
+    let rate_limit = serde_json::from_str::<RateLimitResponse>(body)?;
+    Ok(Duration::try_from_secs_f64(rate_limit.retry_after)?)
+}
+
+/// Sends a request to a webhook, waiting out its rate limit when needed.
+/// `build` is called again for every retry as requests can't be reused.
+pub async fn send<T: DeserializeOwned>(
+    webhook_url: &str,
+    build: impl Fn(&Client) -> RequestBuilder,
+) -> Result<T> {
+    let queue = queue(webhook_url);
+    let _guard = queue.lock().await;
+
+    for _ in 0..MAX_ATTEMPTS {
+        wait_for_bucket(webhook_url).await;
+
+        let res = build(&CLIENT).send().await?;
+        update_bucket(webhook_url, res.headers());
+
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after(&res.text().await?)?;
+            println!(
+                "Rate limited by webhook, retrying after {}s",
+                retry_after.as_secs_f64()
+            );
+            tokio::time::sleep(retry_after).await;
+            continue;
+        }
+
+        return Ok(res.error_for_status()?.json::<T>().await?);
+    }
+
+    bail!("Still rate limited after {MAX_ATTEMPTS} attempts")
+}
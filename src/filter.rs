@@ -0,0 +1,263 @@
+use std::collections::{HashMap, VecDeque};
+
+use regex::Regex;
+
+use crate::chat_service::{self, FullMessage};
+use crate::{discord, matrix, FilterAction, FilterConfig, CONFIG, DATABASE};
+
+/// Window the per user message rate is counted over, in milliseconds
+const RATE_WINDOW: i64 = 60 * 1000;
+
+lazy_static! {
+    // Checked by validate when the bridge starts
+    static ref DENY: Vec<Regex> = CONFIG
+        .filter
+        .deny
+        .iter()
+        .map(|pattern| Regex::new(pattern).expect("Invalid filter deny regex"))
+        .collect();
+    static ref INVITE: Regex =
+        Regex::new(r"(?i)(discord\.gg|discord(app)?\.com/invite)/[\w-]+").unwrap();
+    static ref LINK: Regex = Regex::new(r"(?i)https?://([^/\s:?#]+)").unwrap();
+    /// Matrix mentions in a message's HTML, for clients that don't send m.mentions
+    static ref PILL: Regex = Regex::new(r"https://matrix\.to/#/(@|%40)").unwrap();
+    /// When each user's recent messages were sent
+    static ref RECENT: parking_lot::Mutex<HashMap<String, VecDeque<i64>>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+/// Makes sure the filter's deny regexes are valid, so a typo stops the bridge
+/// starting rather than the first message.
+pub fn validate(filter: &FilterConfig) -> anyhow::Result<()> {
+    for pattern in filter.deny.iter() {
+        if let Err(err) = Regex::new(pattern) {
+            anyhow::bail!("Invalid filter deny regex `{}`: {}", pattern, err);
+        }
+    }
+    Ok(())
+}
+
+/// How many users a Matrix message mentions, from `m.mentions` or else the
+/// mentions in its HTML.
+pub fn matrix_mentions(content: &serde_json::Value) -> usize {
+    if let Some(mentions) = content.get("m.mentions") {
+        let users = mentions["user_ids"].as_array().map_or(0, |ids| ids.len());
+        let room = mentions["room"].as_bool().unwrap_or_default();
+        return users + usize::from(room);
+    }
+    let formatted_body = content["formatted_body"].as_str().unwrap_or_default();
+    let body = content["body"].as_str().unwrap_or_default();
+    PILL.find_iter(formatted_body).count() + body.matches("@room").count()
+}
+
+/// Records a message against its author's rate, returning whether they're over the limit.
+fn over_rate_limit(message: &FullMessage) -> bool {
+    if CONFIG.filter.max_messages_per_minute == 0 {
+        return false;
+    }
+    // Messages relayed late (e.g catching up after a restart) count from when
+    // they were sent, not from when they were relayed
+    let sent = message.timestamp.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    });
+
+    let mut recent = RECENT.lock();
+    let times = recent
+        .entry(format!("{}:{}", message.user.source, message.user.id))
+        .or_default();
+    times.retain(|time| sent - *time < RATE_WINDOW);
+    times.push_back(sent);
+    times.len() > CONFIG.filter.max_messages_per_minute
+}
+
+fn link_allowed(link_allow: &[String], domain: &str) -> bool {
+    let domain = domain.to_lowercase();
+    link_allow
+        .iter()
+        .any(|allowed| domain == *allowed || domain.ends_with(&format!(".{allowed}")))
+}
+
+/// Why a message's content should be caught by the filter, None if it can be
+/// relayed. `mentions` are those not in the content, e.g Matrix pills.
+pub fn check_content(
+    filter: &FilterConfig,
+    deny: &[Regex],
+    content: &str,
+    mentions: usize,
+) -> Option<String> {
+    if let Some(deny) = deny.iter().find(|deny| deny.is_match(content)) {
+        return Some(format!("matches `{}`", deny.as_str()));
+    }
+    if filter.block_invites && INVITE.is_match(content) {
+        return Some("contains an invite link".to_owned());
+    }
+    if !filter.link_allow.is_empty() {
+        let denied = LINK
+            .captures_iter(content)
+            .map(|link| link[1].to_owned())
+            .find(|domain| !link_allowed(&filter.link_allow, domain));
+        if let Some(domain) = denied {
+            return Some(format!("links to {domain}"));
+        }
+    }
+    if filter.max_mentions > 0 {
+        let mentions = mentions
+            + content.matches("<@").count()
+            + content.matches("@everyone").count()
+            + content.matches("@here").count();
+        if mentions > filter.max_mentions {
+            return Some(format!("has {mentions} mentions"));
+        }
+    }
+    None
+}
+
+/// Why a message should be caught by the filter, None if it can be relayed.
+/// Edits don't count towards the rate limit.
+pub fn check(message: &FullMessage, mentions: usize, edit: bool) -> Option<String> {
+    if !edit && over_rate_limit(message) {
+        return Some("sending messages too fast".to_owned());
+    }
+    check_content(&CONFIG.filter, &DENY, &message.content, mentions)
+}
+
+/// Runs a message or an edit through the filter, returning whether it can be
+/// relayed. Caught messages are logged to the admin room, and held for
+/// approval there if that's the filter's action. Redacting is left to the
+/// caller, as only it knows how to on its service. `mentions` are those not
+/// in the content, e.g Matrix pills.
+pub async fn screen(message: &FullMessage, mentions: usize, edit: bool) -> bool {
+    let Some(reason) = check(message, mentions, edit) else {
+        return true;
+    };
+
+    let action = match CONFIG.filter.action {
+        FilterAction::Drop => "Dropped",
+        FilterAction::Redact => "Redacted",
+        FilterAction::Hold => "Held",
+    };
+    let mut log = format!(
+        "{} {} from {} ({}) on {} in {}, it {}:\n{}",
+        action,
+        if edit { "edit" } else { "message" },
+        message.user.display,
+        message.user.tag,
+        message.message.service,
+        message.message.room_id,
+        reason,
+        chat_service::excerpt(&message.content)
+    );
+    if CONFIG.filter.action == FilterAction::Hold {
+        match hold(message, edit) {
+            Ok(id) => log.push_str(&format!(
+                "\nApprove it with !approve {id} or reject it with !reject {id}"
+            )),
+            Err(err) => println!("Failed to hold message: {}", err),
+        }
+    }
+    log_to_admin_room(log).await;
+    false
+}
+
+/// Saves a message until it's approved or rejected, returning its id.
+fn hold(message: &FullMessage, edit: bool) -> anyhow::Result<i64> {
+    let database = DATABASE.lock();
+    database.execute(
+        "INSERT INTO held_messages (message, edit) VALUES (?, ?)",
+        (serde_json::to_string(message)?, edit),
+    )?;
+    Ok(database.last_insert_rowid())
+}
+
+/// Forgets the held messages a user sent.
+pub fn forget(service: &str, user_id: &str) {
+    let database = DATABASE.lock();
+    let held: Vec<(i64, String)> = {
+        let mut stmt = database
+            .prepare("SELECT id, message FROM held_messages")
+            .unwrap();
+        let held = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|held| held.ok())
+            .collect();
+        held
+    };
+    for (id, message) in held {
+        let Ok(message) = serde_json::from_str::<FullMessage>(&message) else {
+            continue;
+        };
+        if message.user.source == service && message.user.id == user_id {
+            let _ = database.execute("DELETE FROM held_messages WHERE id=?", [id]);
+        }
+    }
+}
+
+pub async fn log_to_admin_room(text: String) {
+    println!("{}", text);
+    if let Some(admin_room) = &CONFIG.filter.admin_room {
+        matrix::relay::send_notice(admin_room, text).await;
+    }
+}
+
+/// Relays a held message or edit, or forgets it if it's rejected. Returns
+/// false if there's no held message with the id.
+pub async fn release(id: u64, approved: bool) -> bool {
+    let held = {
+        let database = DATABASE.lock();
+        let held = database
+            .query_row(
+                "SELECT message, edit FROM held_messages WHERE id=?",
+                [id],
+                |row| Ok((row.get::<usize, String>(0)?, row.get::<usize, bool>(1)?)),
+            )
+            .ok();
+        let _ = database.execute("DELETE FROM held_messages WHERE id=?", [id]);
+        held
+    };
+    let Some((message, edit)) = held else {
+        return false;
+    };
+    if !approved {
+        return true;
+    }
+    let message: FullMessage = match serde_json::from_str(&message) {
+        Ok(message) => message,
+        Err(err) => {
+            println!("Failed to load held message {}: {}", id, err);
+            return true;
+        }
+    };
+
+    if edit {
+        match message.message.service.as_str() {
+            "discord" => matrix::relay::edit_message(message).await,
+            _ => {
+                let http = discord::bot::get_context().await.http;
+                discord::relay::edit_message(&http, message).await;
+            }
+        }
+        return true;
+    }
+
+    let relayed = match message.message.service.as_str() {
+        "discord" => matrix::relay::relay_message(message.clone()).await,
+        _ => {
+            let http = discord::bot::get_context().await.http;
+            discord::relay::relay_message(&http, message.clone()).await
+        }
+    };
+    let relayed = match relayed {
+        Ok(relayed) => relayed,
+        Err(err) => {
+            println!("Failed to relay approved message: {}", err);
+            return true;
+        }
+    };
+    chat_service::create_message(message.message.clone(), relayed);
+    chat_service::set_message_author(&message.message, &message.user.id);
+    true
+}
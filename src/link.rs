@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::DATABASE;
+
+/// How long a link code can be used for
+const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+lazy_static! {
+    /// Link codes waiting to be used on Discord, with the Matrix user they were made for
+    static ref CODES: parking_lot::Mutex<HashMap<String, (String, Instant)>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+fn random_code() -> String {
+    const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+    // Codes authenticate links, so they come from a CSPRNG
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+        .collect()
+}
+
+/// Makes a code a Matrix user can use on Discord to link their accounts,
+/// replacing any code they were given before.
+pub fn create_code(matrix_id: &str) -> String {
+    let mut codes = CODES.lock();
+    codes.retain(|_, (user, created)| user != matrix_id && created.elapsed() < CODE_LIFETIME);
+
+    let code = random_code();
+    codes.insert(code.clone(), (matrix_id.to_owned(), Instant::now()));
+    code
+}
+
+/// Links a Discord user to the Matrix user a code was made for, returning the
+/// Matrix user or None if the code is invalid or expired.
+pub fn redeem_code(code: &str, discord_id: &str) -> Option<String> {
+    let (matrix_id, created) = CODES.lock().remove(&code.trim().to_uppercase())?;
+    if created.elapsed() >= CODE_LIFETIME {
+        return None;
+    }
+
+    // Either account may have been linked to another before
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO linked_accounts (discord_id, matrix_id) VALUES (?, ?)",
+            [discord_id, &matrix_id],
+        )
+        .expect("Failed to save linked accounts to database!");
+    Some(matrix_id)
+}
+
+/// Unlinks a Matrix user's Discord account, returning whether they had one linked.
+pub fn unlink(matrix_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .execute("DELETE FROM linked_accounts WHERE matrix_id=?", [matrix_id])
+        .map_or(false, |deleted| deleted > 0)
+}
+
+pub fn linked_matrix_id(discord_id: &str) -> Option<String> {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT matrix_id FROM linked_accounts WHERE discord_id=?",
+            [discord_id],
+            |row| row.get(0),
+        )
+        .ok()
+}
+
+pub fn linked_discord_id(matrix_id: &str) -> Option<String> {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT discord_id FROM linked_accounts WHERE matrix_id=?",
+            [matrix_id],
+            |row| row.get(0),
+        )
+        .ok()
+}
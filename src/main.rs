@@ -12,7 +12,10 @@ use serde::Deserialize;
 
 pub mod chat_service;
 pub mod discord;
+pub mod filter;
+pub mod link;
 pub mod matrix;
+pub mod privacy;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Outer {
@@ -22,14 +25,189 @@ pub struct Outer {
     pub homeserver_url: String,
     pub server_name: String,
 
+    /// Maximum number of Discord messages to relay per channel after a restart
+    #[serde(default = "default_catch_up_limit")]
+    pub catch_up_limit: usize,
+    /// Discord messages older than this many seconds aren't relayed after a restart
+    #[serde(default = "default_catch_up_max_age")]
+    pub catch_up_max_age: u64,
+    /// Messages reaching Discord this many seconds late show when they were sent
+    #[serde(default = "default_delayed_threshold")]
+    pub delayed_threshold: u64,
+
+    /// Matrix power levels given to puppets based on their Discord roles and permissions
+    #[serde(default)]
+    pub power_level: Vec<PowerLevelRule>,
+
+    /// Matrix moderation policy rooms, users banned by them aren't relayed in either direction
+    #[serde(default)]
+    pub policy_rooms: Vec<String>,
+
+    /// as_token of an appservice allowed to act as any user on our homeserver, for
+    /// sending linked users' Discord messages from their Matrix account without
+    /// them having to give the bridge an access token
+    #[serde(default)]
+    pub double_puppet_as_token: Option<String>,
+
+    /// Public URL of the bridge's host, Matrix media linked to on Discord is served from it
+    #[serde(default)]
+    pub media_url: Option<String>,
+
+    /// Command converting Lottie stickers to GIFs, run with {input} and {output}
+    /// replaced by their paths. Lottie stickers are sent as text without one
+    #[serde(default)]
+    pub lottie_converter: Option<String>,
+
+    /// Whether the bot and puppets get their own devices so encrypted rooms can be bridged
+    #[serde(default)]
+    pub encryption: bool,
+    /// Directory the bridge keeps its encryption keys in
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+
+    /// Matrix users who can have DMs with Discord users bridged, DMs sent to the
+    /// Discord bot are bridged to the first
+    #[serde(default)]
+    pub dm_users: Vec<String>,
+
+    /// Filters messages go through before being relayed in either direction
+    #[serde(default)]
+    pub filter: FilterConfig,
+
     pub room: Vec<Entry>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct PowerLevelRule {
+    /// Discord role id
+    pub role: Option<u64>,
+    /// Discord permission, e.g MANAGE_MESSAGES or ADMINISTRATOR
+    pub permission: Option<String>,
+    pub level: i64,
+}
+
+fn default_catch_up_limit() -> usize {
+    100
+}
+
+fn default_catch_up_max_age() -> u64 {
+    60 * 60 * 24
+}
+
+fn default_delayed_threshold() -> u64 {
+    60
+}
+
+fn default_data_dir() -> String {
+    ".".to_owned()
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// Send messages through a webhook with the Matrix user's name
+    #[default]
+    Webhook,
+    /// Send messages as the bot user, prefixed with the Matrix user's name
+    Bot,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyStyle {
+    /// A quote line with the author and start of the replied to message
+    #[default]
+    Quote,
+    /// A single "↪ replying to" line
+    Compact,
+    /// An embed with the author and start of the replied to message
+    Embed,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Don't relay the message
+    #[default]
+    Drop,
+    /// Don't relay the message and remove it where it was sent
+    Redact,
+    /// Don't relay the message unless it's approved in the admin room
+    Hold,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FilterConfig {
+    /// Messages matching any of these regexes are caught
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// If set, messages linking to other domains (or their subdomains) are caught
+    #[serde(default)]
+    pub link_allow: Vec<String>,
+    /// Catch messages with Discord invite links
+    #[serde(default)]
+    pub block_invites: bool,
+    /// Catch messages with more mentions than this, 0 for no limit
+    #[serde(default)]
+    pub max_mentions: usize,
+    /// Catch messages from users sending more than this many a minute, 0 for no limit
+    #[serde(default)]
+    pub max_messages_per_minute: usize,
+    /// What happens to caught messages
+    #[serde(default)]
+    pub action: FilterAction,
+    /// Matrix room caught messages are logged to and held messages are approved in
+    #[serde(default)]
+    pub admin_room: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Entry {
     pub discord: u64,
     pub discord_guild: u64,
     pub matrix: String,
+
+    /// How messages are sent to Discord, if webhooks can't be used the bot is used anyway
+    #[serde(default)]
+    pub relay_mode: RelayMode,
+    /// How Matrix replies are shown on Discord when they can't be real replies
+    #[serde(default)]
+    pub reply_style: ReplyStyle,
+
+    /// Apply Discord channel name changes to the Matrix room name
+    #[serde(default)]
+    pub name_to_matrix: bool,
+    /// Apply Discord channel topic changes to the Matrix room topic
+    #[serde(default)]
+    pub topic_to_matrix: bool,
+    /// Apply Matrix room name changes to the Discord channel name
+    #[serde(default)]
+    pub name_to_discord: bool,
+    /// Apply Matrix room topic changes to the Discord channel topic
+    #[serde(default)]
+    pub topic_to_discord: bool,
+
+    /// Number of Discord messages to relay when the channel is first bridged
+    #[serde(default)]
+    pub backfill: usize,
+    /// Number of Matrix messages to post as a digest when the room is first bridged
+    #[serde(default)]
+    pub backfill_to_discord: usize,
+
+    /// If set, only messages from these Discord bots are relayed
+    #[serde(default)]
+    pub bot_allow: Vec<u64>,
+    /// Messages from these Discord bots are never relayed
+    #[serde(default)]
+    pub bot_deny: Vec<u64>,
+
+    /// Send a notice on Matrix when someone joins or leaves the Discord channel
+    #[serde(default)]
+    pub member_notices: bool,
+
+    /// Ban Discord users from the guild when their puppet is banned on Matrix
+    #[serde(default)]
+    pub ban_to_discord: bool,
 }
 
 lazy_static! {
@@ -65,11 +243,12 @@ pub fn load_config() -> Outer {
 pub async fn init_statics() -> anyhow::Result<()> {
     let config_str: String = std::fs::read_to_string("./config.toml").ok().unwrap();
     let config_parsed: Outer = toml::from_str(&config_str)?;
+    filter::validate(&config_parsed.filter)?;
 
     // lock().unwrap() doesn't work here, but try_lock() does.
     DATABASE
         .lock()
-        .execute(
+        .execute_batch(
             "
             CREATE TABLE IF NOT EXISTS messages (
                 id  INTEGER PRIMARY KEY,
@@ -80,16 +259,122 @@ pub async fn init_statics() -> anyhow::Result<()> {
                 service_out TEXT NOT NULL,
                 server_id_out   TEXT NOT NULL,
                 room_id_out TEXT NOT NULL,
-                id_out  TEXT NOT NULL UNIQUE
-            )
+                id_out  TEXT NOT NULL UNIQUE,
+                author_org  TEXT
+            );
             CREATE TABLE IF NOT EXISTS discord_channels (
                 id  INTEGER PRIMARY KEY,
-                webhook_token TEXT NOT NULL,
-            }
+                webhook_id  INTEGER,
+                webhook_token TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS puppets (
+                user_id TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS puppet_rooms (
+                user_id TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                PRIMARY KEY (user_id, room_id)
+            );
+            CREATE TABLE IF NOT EXISTS puppet_names (
+                user_id TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                display_name    TEXT NOT NULL,
+                avatar_url  TEXT,
+                PRIMARY KEY (user_id, room_id)
+            );
+            CREATE TABLE IF NOT EXISTS puppet_avatars (
+                discord_url TEXT PRIMARY KEY,
+                mxc_uri TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS held_messages (
+                id  INTEGER PRIMARY KEY,
+                message TEXT NOT NULL,
+                edit    INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS reactions (
+                message_id  TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                event_id    TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id, key)
+            );
+            CREATE TABLE IF NOT EXISTS matrix_reactions (
+                event_id    TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                reacted_id  TEXT NOT NULL,
+                reaction    TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS linked_media (
+                mxc_uri TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS stickers (
+                discord_url TEXT PRIMARY KEY,
+                mxc_uri TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS lottie_stickers (
+                discord_url TEXT PRIMARY KEY,
+                mxc_uri TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS emoji (
+                discord_id  TEXT PRIMARY KEY,
+                name    TEXT NOT NULL,
+                mxc_uri TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS bridge_state (
+                key TEXT PRIMARY KEY,
+                value   TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS bans (
+                service TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                PRIMARY KEY (service, user_id, room_id)
+            );
+            CREATE TABLE IF NOT EXISTS linked_accounts (
+                discord_id  TEXT PRIMARY KEY,
+                matrix_id   TEXT NOT NULL UNIQUE
+            );
+            CREATE TABLE IF NOT EXISTS double_puppets (
+                matrix_id   TEXT PRIMARY KEY,
+                access_token    TEXT NOT NULL,
+                device_id   TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS opt_outs (
+                service TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                notified    INTEGER NOT NULL,
+                PRIMARY KEY (service, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS dm_rooms (
+                discord_user    TEXT PRIMARY KEY,
+                channel_id  TEXT NOT NULL,
+                room_id TEXT NOT NULL UNIQUE
+            );
+            CREATE TABLE IF NOT EXISTS moderation_log (
+                id  INTEGER PRIMARY KEY,
+                time    INTEGER NOT NULL,
+                source  TEXT NOT NULL,
+                action  TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                reason  TEXT
+            );
         ",
-            (),
         )
-        .expect("Should have created message");
+        .expect("Should have created tables");
+    // Databases from before webhook ids were saved, errors if the column already exists
+    let _ = DATABASE.lock().execute(
+        "ALTER TABLE discord_channels ADD COLUMN webhook_id INTEGER",
+        (),
+    );
+    let _ = DATABASE
+        .lock()
+        .execute("ALTER TABLE puppet_names ADD COLUMN avatar_url TEXT", ());
+    let _ = DATABASE
+        .lock()
+        .execute("ALTER TABLE messages ADD COLUMN author_org TEXT", ());
 
     for val in config_parsed.room.iter() {
         println!("{} -> {}", val.discord, val.matrix);
@@ -192,4 +477,483 @@ mod tests {
         let relays_noexist = chat_service::message_relays(fake_msg2.clone());
         assert_eq!(relays_noexist.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_db_state() {
+        init_tests().await;
+
+        chat_service::set_state("test_key", "first");
+        chat_service::set_state("test_key", "second");
+        assert_eq!(chat_service::get_state("test_key").unwrap(), "second");
+
+        assert!(chat_service::get_state("test_key_noexist").is_none());
+    }
+
+    #[test]
+    fn test_policy_glob() {
+        use matrix::policy::glob_matches;
+
+        assert!(glob_matches("@spam:example.com", "@spam:example.com"));
+        assert!(glob_matches("*.example.com", "matrix.example.com"));
+        assert!(glob_matches("@spam?:*", "@spam1:example.com"));
+        assert!(!glob_matches("@spam?:*", "@spam:example.com"));
+        assert!(!glob_matches("example.com", "example.org"));
+    }
+
+    #[test]
+    fn test_filter_rules() {
+        use regex::Regex;
+
+        let config = FilterConfig {
+            link_allow: vec!["example.com".to_owned()],
+            block_invites: true,
+            max_mentions: 2,
+            ..Default::default()
+        };
+        let deny = vec![Regex::new("(?i)free nitro").unwrap()];
+        let check = |content: &str, mentions: usize| {
+            filter::check_content(&config, &deny, content, mentions)
+        };
+
+        assert!(check("hello", 0).is_none());
+        assert!(check("FREE NITRO here", 0).is_some());
+        assert!(check("join discord.gg/abc", 0).is_some());
+        assert!(check("see https://docs.example.com/page", 0).is_none());
+        assert!(check("see https://example.org", 0).is_some());
+        assert!(check("<@1> <@2>", 0).is_none());
+        assert!(check("<@1> <@2> @everyone", 0).is_some());
+        assert!(check("<@1>", 2).is_some());
+
+        assert!(filter::validate(&FilterConfig {
+            deny: vec!["(unclosed".to_owned()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_matrix_mentions() {
+        use serde_json::json;
+
+        let content = json!({ "m.mentions": { "user_ids": ["@a:x", "@b:x"], "room": true } });
+        assert_eq!(filter::matrix_mentions(&content), 3);
+        let content = json!({
+            "body": "a b @room",
+            "formatted_body": "<a href=\"https://matrix.to/#/@a:x\">a</a> <a href=\"https://matrix.to/#/%40b:x\">b</a> @room",
+        });
+        assert_eq!(filter::matrix_mentions(&content), 3);
+    }
+
+    #[test]
+    fn test_closest_emoji() {
+        use serenity::model::prelude::Emoji;
+
+        let emoji = |id: u64, name: &str| -> Emoji {
+            serde_json::from_value(serde_json::json!({
+                "id": id.to_string(),
+                "name": name,
+                "animated": false,
+                "available": true,
+                "managed": false,
+                "require_colons": true,
+                "roles": [],
+            }))
+            .unwrap()
+        };
+        let emojis = vec![emoji(1, "Blob"), emoji(2, "blob")];
+
+        let closest = |name| discord::emoji::closest_by_name(&emojis, name).map(|e| e.id.0);
+        assert_eq!(closest(":blob:"), Some(2));
+        assert_eq!(closest("Blob"), Some(1));
+        assert_eq!(closest("BLOB"), Some(1));
+        assert_eq!(closest("blobby"), None);
+        assert_eq!(closest("lob"), None);
+        assert_eq!(closest(""), None);
+    }
+
+    #[test]
+    fn test_emotes_to_discord() {
+        use discord::emoji::{emotes_in, replace_emotes};
+
+        let html = "hi <img data-mx-emoticon src=\"mxc://x/a\" alt=\":wave:\" height=\"32\"> \
+            <img data-mx-emoticon src=\"mxc://x/b\" title=\":cat:\">";
+        assert_eq!(
+            emotes_in(html),
+            vec![
+                ("mxc://x/a".to_owned(), ":wave:".to_owned()),
+                ("mxc://x/b".to_owned(), ":cat:".to_owned()),
+            ]
+        );
+
+        let replacements = vec![
+            (":wave:".to_owned(), "<:wave:1>".to_owned()),
+            (":wave:".to_owned(), "<:wave:1>".to_owned()),
+        ];
+        assert_eq!(
+            replace_emotes(":wave: and :wave:", &replacements),
+            "<:wave:1> and <:wave:1>"
+        );
+        assert_eq!(replace_emotes(":cat:", &replacements), ":cat:");
+    }
+
+    #[test]
+    fn test_emoji_to_matrix() {
+        use matrix::emotes::{emoji_images, emoji_to_text};
+
+        assert_eq!(emoji_to_text("hi <:wave:1> <a:cat:2>"), "hi :wave: :cat:");
+
+        let uploaded: Vec<(String, Option<ruma::OwnedMxcUri>)> = vec![
+            ("1".to_owned(), Some("mxc://x/a".into())),
+            ("2".to_owned(), None),
+        ];
+        assert_eq!(
+            emoji_images("&lt;:wave:1&gt; &lt;a:cat:2&gt;", &uploaded),
+            "<img data-mx-emoticon src=\"mxc://x/a\" alt=\":wave:\" title=\":wave:\" height=\"32\"> :cat:"
+        );
+    }
+
+    #[test]
+    fn test_webhook_retry_after() {
+        use discord::webhook::retry_after;
+        use std::time::Duration;
+
+        let body =
+            r#"{"message": "You are being rate limited.", "retry_after": 0.5, "global": false}"#;
+        assert_eq!(retry_after(body).unwrap(), Duration::from_millis(500));
+        assert_eq!(
+            retry_after(r#"{"retry_after": 2}"#).unwrap(),
+            Duration::from_secs(2)
+        );
+        assert!(retry_after("not json").is_err());
+    }
+
+    #[test]
+    fn test_reply_styles() {
+        use chat_service::{FullMessage, ReplyPreview, User};
+        use discord::relay::render_message;
+
+        let user = User {
+            source: "matrix".to_owned(),
+            id: "@a:x".to_owned(),
+            ping: "<@1>".to_owned(),
+            tag: "@a:x".to_owned(),
+            display: "A".to_owned(),
+            avatar: None,
+        };
+        let message = FullMessage {
+            user: user.clone(),
+            message: Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: "!r:x".to_owned(),
+                id: "$e".to_owned(),
+            },
+            content: "reply".to_owned(),
+            reply: None,
+            reply_preview: Some(ReplyPreview {
+                user,
+                excerpt: "original".to_owned(),
+                mention: false,
+            }),
+            timestamp: None,
+            sticker: None,
+            attachments: vec![],
+        };
+        let room = |style: &str| -> Entry {
+            toml::from_str(&format!(
+                "discord = 1\ndiscord_guild = 2\nmatrix = \"!r:x\"\nreply_style = \"{style}\""
+            ))
+            .unwrap()
+        };
+
+        let quote = render_message(&room("quote"), &message, false);
+        assert_eq!(quote.content, "> **A** original\nreply");
+        let compact = render_message(&room("compact"), &message, false);
+        assert_eq!(compact.content, "↪ replying to **A**: original\nreply");
+        let embed = render_message(&room("embed"), &message, false);
+        assert_eq!(embed.content, "reply");
+        assert_eq!(embed.embed.unwrap().description, "original");
+        // Real replies don't need a preview
+        let native = render_message(&room("quote"), &message, true);
+        assert_eq!(native.content, "reply");
+    }
+
+    #[tokio::test]
+    async fn test_puppet_repair() {
+        use matrix::puppet;
+        use ruma::{RoomId, UserId};
+
+        init_tests().await;
+
+        let user_id = UserId::parse("@_discord_1:example.com").unwrap();
+        let room_id = RoomId::parse("!repair:example.com").unwrap();
+        puppet::remember_room(user_id.as_str(), room_id.as_str()).unwrap();
+        assert!(puppet::is_joined(user_id.as_str(), room_id.as_str()));
+
+        // After repairing, get_room joins again instead of trusting the client's store
+        puppet::repair(&user_id, &room_id).await;
+        assert!(!puppet::is_joined(user_id.as_str(), room_id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_puppet_rejoin_after_leave() {
+        use matrix::puppet;
+
+        init_tests().await;
+
+        // Leaving and being banned forget the membership, so joining again
+        // in the same process doesn't trust the client's stale store
+        let (user_id, room_id) = ("@_discord_2:example.com", "!rejoin:example.com");
+        puppet::remember_room(user_id, room_id).unwrap();
+        puppet::forget_room(user_id, room_id).unwrap();
+        assert!(!puppet::is_joined(user_id, room_id));
+        puppet::remember_room(user_id, room_id).unwrap();
+        assert!(puppet::is_joined(user_id, room_id));
+    }
+
+    #[test]
+    fn test_member_power_level() {
+        use discord::bot::member_power_level;
+        use serenity::model::{id::RoleId, Permissions};
+
+        let rule = |role: Option<u64>, permission: Option<&str>, level| PowerLevelRule {
+            role,
+            permission: permission.map(|permission| permission.to_owned()),
+            level,
+        };
+        let rules = vec![
+            rule(Some(1), None, 20),
+            rule(None, Some("MANAGE_MESSAGES"), 50),
+            rule(None, Some("administrator"), 100),
+        ];
+
+        let cases = [
+            (vec![], Permissions::empty(), 0),
+            (vec![RoleId(1)], Permissions::empty(), 20),
+            (vec![RoleId(2)], Permissions::MANAGE_MESSAGES, 50),
+            // The highest matching rule wins
+            (vec![RoleId(1)], Permissions::MANAGE_MESSAGES, 50),
+            (
+                vec![RoleId(1)],
+                Permissions::ADMINISTRATOR | Permissions::MANAGE_MESSAGES,
+                100,
+            ),
+        ];
+        for (roles, permissions, level) in cases {
+            assert_eq!(member_power_level(&rules, &roles, permissions), level);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_db_matrix_reactions() {
+        init_tests().await;
+
+        let event = |id: &str| Message {
+            service: "matrix".to_owned(),
+            server_id: "".to_owned(),
+            room_id: "!reactions:example.com".to_owned(),
+            id: id.to_owned(),
+        };
+        let reacted = event("$reacted");
+        chat_service::save_matrix_reaction(&event("$a"), "@a:example.com", &reacted, "👍");
+        chat_service::save_matrix_reaction(&event("$b"), "@b:example.com", &reacted, "👍");
+
+        // The bot's reaction stays while anyone else still has the same one
+        let (message, key, shared) = chat_service::take_matrix_reaction(&event("$a")).unwrap();
+        assert_eq!(
+            (message.id.as_str(), key.as_str(), shared),
+            ("$reacted", "👍", true)
+        );
+        let (_, _, shared) = chat_service::take_matrix_reaction(&event("$b")).unwrap();
+        assert!(!shared);
+        assert!(chat_service::take_matrix_reaction(&event("$b")).is_none());
+    }
+
+    #[test]
+    fn test_ping_user_id() {
+        use discord::relay::ping_user_id;
+        use serenity::model::id::UserId;
+
+        assert_eq!(ping_user_id("<@123>"), Some(UserId(123)));
+        assert_eq!(ping_user_id("<@!123>"), Some(UserId(123)));
+        // Matrix users aren't pinged on Discord
+        assert_eq!(ping_user_id("@a:example.com"), None);
+        assert_eq!(ping_user_id("<@@a:example.com>"), None);
+    }
+
+    #[test]
+    fn test_image_extension() {
+        use discord::relay::image_extension;
+
+        assert_eq!(image_extension(b"GIF89a\x01\x00"), "gif");
+        assert_eq!(image_extension(b"RIFF\x24\x00\x00\x00WEBPVP8 "), "webp");
+        assert_eq!(image_extension(b"\x89PNG\r\n\x1a\n"), "png");
+        // Too short to be a WebP
+        assert_eq!(image_extension(b"RIFF\x24\x00\x00\x00WEBP"), "png");
+    }
+
+    #[test]
+    fn test_catch_up_cutoff() {
+        use discord::bot::{catch_up_stop, message_id_timestamp, truncate_oldest, CatchUpStop};
+        use serenity::model::id::MessageId;
+
+        // The example snowflake from Discord's docs
+        assert_eq!(
+            message_id_timestamp(MessageId(175928847299117063)),
+            1462015105796
+        );
+
+        let at = |ms: u64| MessageId((ms - 1420070400000) << 22);
+        let last = at(1_600_000_000_000);
+        let oldest = 1_700_000_000_000;
+        assert_eq!(
+            catch_up_stop(last, last, oldest),
+            Some(CatchUpStop::Relayed)
+        );
+        assert_eq!(
+            catch_up_stop(at(1_650_000_000_000), last, oldest),
+            Some(CatchUpStop::TooOld)
+        );
+        assert_eq!(catch_up_stop(at(1_750_000_000_000), last, oldest), None);
+
+        let mut missed = vec![1, 2, 3];
+        assert!(!truncate_oldest(&mut missed, 3));
+        assert!(truncate_oldest(&mut missed, 2));
+        assert_eq!(missed, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_channel_name_matches() {
+        use discord::relay::channel_name_matches;
+
+        assert!(channel_name_matches("general", "general"));
+        // Discord's version of a name set from Matrix
+        assert!(channel_name_matches("off-topic-chat", "Off Topic Chat"));
+        // Voice channels keep their name as is
+        assert!(channel_name_matches("Off Topic Chat", "Off Topic Chat"));
+        assert!(!channel_name_matches("general", "General Chat"));
+        assert!(!channel_name_matches("off-topic", "Off Topic Chat"));
+    }
+
+    #[test]
+    fn test_sent_time_suffix() {
+        use discord::relay::sent_time_suffix;
+
+        let sent = 1_700_000_000_000;
+        let cases = [
+            (sent + 5_000, "hi"),
+            (sent + 59_999, "hi"),
+            (sent + 60_000, "hi\n-# sent <t:1700000000:f>"),
+            (sent + 3_600_000, "hi\n-# sent <t:1700000000:f>"),
+        ];
+        for (now, expected) in cases {
+            assert_eq!(sent_time_suffix("hi".to_owned(), sent, now, 60), expected);
+        }
+    }
+
+    #[test]
+    fn test_embed_to_markdown() {
+        use discord::bot::embed_to_markdown;
+        use serenity::model::channel::Embed;
+
+        let embed = |json: serde_json::Value| -> Embed { serde_json::from_value(json).unwrap() };
+        let cases = [
+            (serde_json::json!({}), ""),
+            (
+                serde_json::json!({ "title": "Release", "url": "https://example.com" }),
+                "> **[Release](https://example.com)**",
+            ),
+            (
+                serde_json::json!({
+                    "title": "Build",
+                    "description": "Passed\nin 5m",
+                    "fields": [{ "name": "Branch", "value": "main", "inline": true }],
+                }),
+                "> **Build**\n> Passed\n> in 5m\n> **Branch**: main",
+            ),
+        ];
+        for (json, expected) in cases {
+            assert_eq!(embed_to_markdown(&embed(json)), expected);
+        }
+    }
+
+    #[test]
+    fn test_bot_allowed() {
+        use discord::bot::bot_allowed;
+
+        let room = |lists: &str| -> Entry {
+            toml::from_str(&format!(
+                "discord = 1\ndiscord_guild = 2\nmatrix = \"!r:x\"\n{lists}"
+            ))
+            .unwrap()
+        };
+        let cases = [
+            ("", 10, true),
+            ("bot_deny = [10]", 10, false),
+            ("bot_deny = [10]", 11, true),
+            ("bot_allow = [10]", 10, true),
+            ("bot_allow = [10]", 11, false),
+            // Denying wins over allowing
+            ("bot_allow = [10]\nbot_deny = [10]", 10, false),
+        ];
+        for (lists, bot_id, allowed) in cases {
+            assert_eq!(bot_allowed(&room(lists), bot_id), allowed);
+        }
+    }
+
+    #[test]
+    fn test_reply_excerpt() {
+        use matrix::bot::reply_excerpt;
+        use serde_json::json;
+
+        let cases = [
+            (json!({}), "Message unavailable"),
+            (json!({ "msgtype": "m.text", "body": "hello" }), "hello"),
+            // The fallback of what it replied to isn't part of it
+            (
+                json!({ "msgtype": "m.text", "body": "> <@a:x> quoted\n\nanswer" }),
+                "answer",
+            ),
+            (
+                json!({ "msgtype": "m.image", "body": "cat.png" }),
+                "🖼️ Image",
+            ),
+            (
+                json!({ "msgtype": "m.file", "body": "notes.txt" }),
+                "📎 notes.txt",
+            ),
+        ];
+        for (content, excerpt) in cases {
+            assert_eq!(reply_excerpt(&content), excerpt);
+        }
+    }
+
+    #[test]
+    fn test_reply_fallback() {
+        use matrix::relay::{quote_fallback, reply_fallback};
+
+        let (body, html_body) = reply_fallback(
+            "!r:x",
+            "$e",
+            "@a:x",
+            "<b>hi</b>",
+            ("reply".to_owned(), "<p>reply</p>".to_owned()),
+        );
+        assert_eq!(body, "> <@a:x> <b>hi</b>\n\nreply");
+        assert_eq!(
+            html_body,
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/!r:x/$e\">In reply to</a> <a href=\"https://matrix.to/#/@a:x\">@a:x</a><br>&lt;b&gt;hi&lt;/b&gt;</blockquote></mx-reply><p>reply</p>"
+        );
+
+        let (body, html_body) = quote_fallback(
+            "A & B (a#1)",
+            "hi",
+            ("reply".to_owned(), "<p>reply</p>".to_owned()),
+        );
+        assert_eq!(body, "> A & B (a#1): hi\n\nreply");
+        assert_eq!(
+            html_body,
+            "<blockquote><b>A &amp; B (a#1)</b>: hi</blockquote><p>reply</p>"
+        );
+    }
 }
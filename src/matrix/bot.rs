@@ -1,31 +1,53 @@
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
+use axum::routing::get;
 use futures::future;
 use matrix_sdk::room::Joined;
 use ruma::{
-    events::room::{
-        message::{OriginalSyncRoomMessageEvent, Relation},
-        redaction::OriginalSyncRoomRedactionEvent,
+    events::{
+        reaction::OriginalSyncReactionEvent,
+        room::{
+            encryption::OriginalSyncRoomEncryptionEvent,
+            member::{
+                MembershipChange, MembershipState, OriginalSyncRoomMemberEvent,
+                StrippedRoomMemberEvent,
+            },
+            message::{OriginalSyncRoomMessageEvent, Relation},
+            name::OriginalSyncRoomNameEvent,
+            redaction::OriginalSyncRoomRedactionEvent,
+            topic::OriginalSyncRoomTopicEvent,
+        },
+        sticker::OriginalSyncStickerEvent,
     },
-    EventId, OwnedEventId, RoomId,
+    EventId, OwnedEventId, RoomId, UInt, UserId,
 };
 
 use matrix_sdk_appservice::{
-    matrix_sdk::{config::SyncSettings, room::Room, sync::SyncResponse, Client},
+    matrix_sdk::{
+        config::SyncSettings,
+        event_handler::RawEvent,
+        room::{MessagesOptions, Room},
+        sync::SyncResponse,
+        Client, LoopCtrl,
+    },
     AppService, AppServiceBuilder, AppServiceRegistration, Result,
 };
 use serenity::http::Http;
 
 use crate::{
-    chat_service::{self, FullMessage, Message, User},
-    discord::{self, bot::CONTEXT},
-    CONFIG,
+    chat_service::{self, FullMessage, Message, ReplyPreview, Sticker, User},
+    discord, filter, link, privacy, Entry, FilterAction, CONFIG,
 };
 
+use super::{double_puppet, encryption, policy};
+
 pub static BOT_APPSERVICE: Mutex<Option<AppService>> = Mutex::new(None);
 pub static BOT_REGISTRATION: Mutex<Option<AppServiceRegistration>> = Mutex::new(None);
 pub static BOT_CLIENT: Mutex<Option<Client>> = Mutex::new(None);
 
+const SYNC_TOKEN_KEY: &str = "matrix_next_batch";
+
 fn find_ping(ping: String) -> String {
     let user = ping
         .trim_start_matches("<")
@@ -39,6 +61,8 @@ fn find_ping(ping: String) -> String {
 
     if localpart.starts_with(bot_localpart.as_str()) {
         return format!("<@{}>", &localpart[bot_localpart.len()..]);
+    } else if let Some(discord_id) = link::linked_discord_id(&format!("@{}", user)) {
+        return format!("<@{}>", discord_id);
     } else {
         return ping
             .trim_start_matches("<")
@@ -59,34 +83,58 @@ fn strip_reply(msg: String) -> String {
     return actual_message;
 }
 
+/// Short description of a message, used to preview it in replies.
+pub fn reply_excerpt(content: &serde_json::Value) -> String {
+    let body = content["body"]
+        .as_str()
+        .map(|body| strip_reply(body.to_owned()));
+    let Some(body) = body else {
+        return "Message unavailable".to_owned();
+    };
+
+    match content["msgtype"].as_str() {
+        Some("m.image") => return "🖼️ Image".to_owned(),
+        Some("m.video") => return "🎞️ Video".to_owned(),
+        Some("m.audio") => return "🔊 Audio".to_owned(),
+        Some("m.file") => return format!("📎 {}", body.trim()),
+        _ => {}
+    }
+
+    chat_service::excerpt(&body)
+}
+
 async fn format_for_reply_event_id(
     message: FullMessage,
     reply_id: OwnedEventId,
     content: String,
     room: Joined,
+    mentions: Vec<String>,
 ) -> FullMessage {
     let mut relay_msg = message.clone();
+    relay_msg.content = strip_reply(content);
 
-    let mut reply_header = "".to_owned();
-    let reply_data = room
-        .event(&reply_id)
-        .await
-        .unwrap()
-        .event
-        .json()
-        .to_string();
+    let reply_data = match room.event(&reply_id).await {
+        Ok(reply_event) => reply_event.event.json().to_string(),
+        Err(err) => {
+            println!("Couldn't get replied to event: {}", err);
+            return relay_msg;
+        }
+    };
     let v: serde_json::Value = serde_json::from_str(&reply_data).unwrap();
 
-    let mut reply_body = v["content"]["body"].as_str().unwrap().to_owned();
-    reply_body = strip_reply(reply_body);
-
-    let reply_author = v["sender"].as_str().unwrap().to_owned();
-    let author_ping = find_ping(reply_author);
-
-    let mut header = reply_body.lines().collect::<Vec<&str>>()[0].to_owned();
-    if header.len() > 64 {
-        header = format!("{}...", &header[..64]);
-    }
+    let reply_author = v["sender"].as_str().unwrap_or_default().to_owned();
+    let author_ping = find_ping(reply_author.clone());
+    let author_name = match UserId::parse(&reply_author) {
+        Ok(sender) => room
+            .get_member(&sender)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|member| member.display_name().map(|name| name.to_owned())),
+        Err(_) => None,
+    };
+    // Puppets and linked users are pinged as their Discord user
+    let is_puppet = author_ping.starts_with("<@");
 
     let reply_msg = Message {
         service: "matrix".to_owned(),
@@ -95,54 +143,48 @@ async fn format_for_reply_event_id(
         id: reply_id.to_string(),
     };
 
-    let relayed_messages = chat_service::message_relays(reply_msg.clone());
-    let mut discord_msg_url = "".to_owned();
-    for msg in relayed_messages {
-        if msg.service == "discord" {
-            discord_msg_url = format!(
-                "https://discord.com/channels/{}/{}/{}",
-                msg.server_id, msg.room_id, msg.id
-            );
-        }
-    }
-    let origin_message = chat_service::message_origin(reply_msg.clone());
-    if origin_message.is_some() {
-        if origin_message.clone().unwrap().service == "discord" {
-            discord_msg_url = format!(
-                "https://discord.com/channels/{}/{}/{}",
-                origin_message.clone().unwrap().server_id,
-                origin_message.clone().unwrap().room_id,
-                origin_message.clone().unwrap().id
-            );
-        }
-    }
-
-    if discord_msg_url != "" {
-        header = format!("[{}]({})", header, discord_msg_url).to_owned();
-    }
-    //https://discord.com/channels/server/channel/msg
-    reply_header = format!("> {} {}", author_ping, header);
-
-    relay_msg.content = format!("{}\n{}", reply_header, strip_reply(content));
+    relay_msg.reply = Some(Box::new(reply_msg));
+    relay_msg.reply_preview = Some(ReplyPreview {
+        user: User {
+            source: if is_puppet { "discord" } else { "matrix" }.to_owned(),
+            id: reply_author.clone(),
+            ping: author_ping,
+            tag: reply_author.clone(),
+            display: author_name.unwrap_or(reply_author.clone()),
+            avatar: None,
+        },
+        excerpt: reply_excerpt(&v["content"]),
+        mention: is_puppet && mentions.contains(&reply_author),
+    });
     return relay_msg;
 }
 
+/// Users the sender intended to mention, from `m.mentions` in the event content.
+fn intended_mentions(content: &serde_json::Value) -> Vec<String> {
+    content["m.mentions"]["user_ids"]
+        .as_array()
+        .map(|user_ids| {
+            user_ids
+                .iter()
+                .filter_map(|user_id| user_id.as_str().map(|user_id| user_id.to_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 async fn format_for_reply(
     message: FullMessage,
     event: OriginalSyncRoomMessageEvent,
     room: Joined,
+    mentions: Vec<String>,
 ) -> FullMessage {
     if event.content.relates_to.is_some() {
         match event.content.clone().relates_to.unwrap() {
             Relation::Reply { in_reply_to } => {
                 let reply_id = in_reply_to.event_id;
-                return format_for_reply_event_id(
-                    message,
-                    reply_id,
-                    event.content.body().to_owned(),
-                    room,
-                )
-                .await;
+                // The content already has emotes converted
+                let content = message.content.clone();
+                return format_for_reply_event_id(message, reply_id, content, room, mentions).await;
             }
             _ => {}
         }
@@ -150,7 +192,7 @@ async fn format_for_reply(
     return message;
 }
 
-async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
+async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room, raw: RawEvent) {
     println!("GOT MESSAGE");
     println!("{}", event.content.body());
 
@@ -160,13 +202,40 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
     if event.sender.localpart().starts_with(&bot_localpart) {
         return;
     }
+    let raw: serde_json::Value = serde_json::from_str(raw.get()).unwrap_or_default();
+    // Sent by the bridge from a double puppeted user's account, so it came from Discord
+    if raw["content"].get(double_puppet::SOURCE_KEY).is_some() {
+        return;
+    }
 
     if let Room::Joined(room) = room {
+        if CONFIG.filter.admin_room.as_deref() == Some(room.room_id().as_str()) {
+            handle_admin_command(event.content.body()).await;
+            return;
+        }
+        let body = event.content.body();
+        let command = body.split_whitespace().next().unwrap_or_default();
+        if ["!link", "!unlink", "!login", "!logout"].contains(&command) {
+            handle_link_command(&event.sender, &event.event_id, &room, body).await;
+            return;
+        }
+        if ["!optout", "!optin", "!forgetme"].contains(&command) {
+            handle_privacy_command(&event.sender, &room, command).await;
+            return;
+        }
+        if command == "!dm" {
+            handle_dm_command(&event.sender, &room, body).await;
+            return;
+        }
+
         let m = CONFIG
             .room
             .iter()
             .find(|m| m.matrix == room.room_id().to_string());
         if m.is_none() {
+            if let Some(channel_id) = super::dm::channel_for_room(room.room_id().as_str()) {
+                relay_dm_message(event, room, channel_id).await;
+            }
             return;
         }
 
@@ -177,7 +246,33 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
             id: event.event_id.to_string(),
         };
 
-        let user = User {
+        // Events can be seen again when resuming a sync after a restart
+        if chat_service::message_relayed(msg.clone()) {
+            return;
+        }
+        // e.g messages sent before a ban but only seen after it
+        if chat_service::is_banned("matrix", event.sender.as_str(), &msg.room_id) {
+            return;
+        }
+        if privacy::is_opted_out("matrix", event.sender.as_str()) {
+            if privacy::take_notice("matrix", event.sender.as_str()) {
+                let notice = format!(
+                    "{}'s messages aren't bridged to Discord, as they opted out",
+                    event.sender
+                );
+                super::relay::send_notice(room.room_id().as_str(), notice).await;
+            }
+            return;
+        }
+        if policy::is_banned(&event.sender) {
+            println!(
+                "Not relaying message from {}, banned by policy",
+                event.sender
+            );
+            return;
+        }
+
+        let mut user = User {
             source: "matrix".to_owned(),
             id: event.sender.to_string(),
             ping: format!("<@{}>", event.sender.to_string()),
@@ -185,12 +280,32 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
             display: event.sender.to_string(),
             avatar: None,
         };
+        // Linked users show up on Discord as their Discord account
+        if let Some(discord_id) = link::linked_discord_id(event.sender.as_str()) {
+            let guild_id = m.unwrap().discord_guild;
+            if let Some(linked) = discord::bot::linked_user(&discord_id, guild_id).await {
+                user.display = linked.display;
+                user.tag = linked.tag;
+                user.avatar = linked.avatar;
+            }
+        }
 
+        // Emotes become the closest guild emoji
+        let content = discord::emoji::emotes_to_discord(
+            event.content.body(),
+            raw["content"]["formatted_body"].as_str(),
+            m.unwrap().discord_guild,
+        )
+        .await;
         let mut relay_msg = FullMessage {
             message: msg,
             user: user,
-            content: event.content.body().to_string(),
+            content,
             reply: None,
+            reply_preview: None,
+            timestamp: Some(event.origin_server_ts.get().into()),
+            sticker: None,
+            attachments: vec![],
         };
         //let content = RoomMessageEventContent::text_plain("🎉🎊🥳 let's PARTY!! 🥳🎊🎉");
 
@@ -198,6 +313,16 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
             match event.content.clone().relates_to.unwrap() {
                 Relation::Replacement(r) => {
                     let event_id = r.event_id;
+                    // The event's own content is the "* " fallback, the edit is in m.new_content
+                    let new_content = &raw["content"]["m.new_content"];
+                    if let Some(body) = new_content["body"].as_str() {
+                        relay_msg.content = discord::emoji::emotes_to_discord(
+                            body,
+                            new_content["formatted_body"].as_str(),
+                            m.unwrap().discord_guild,
+                        )
+                        .await;
+                    }
                     let edit_data = room
                         .event(&event_id)
                         .await
@@ -218,14 +343,27 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
                             relay_msg.clone(),
                             reply_event,
                             relay_msg.clone().content,
-                            room,
+                            room.clone(),
+                            intended_mentions(&v["content"]),
                         )
                         .await;
                     } else {
                         println!("Isn't reply!");
                     }
 
-                    let http = CONTEXT.lock().clone().unwrap().http;
+                    let mentions = filter::matrix_mentions(&raw["content"]["m.new_content"]);
+                    if !filter::screen(&relay_msg, mentions, true).await {
+                        // Its copy on Discord goes along with it
+                        if CONFIG.filter.action == FilterAction::Redact {
+                            if let Err(err) =
+                                room.redact(&event_id, Some("Caught by filter"), None).await
+                            {
+                                println!("Failed to redact filtered message: {}", err);
+                            }
+                        }
+                        return;
+                    }
+                    let http = discord::bot::get_context().await.http;
 
                     discord::relay::edit_message(&http, relay_msg).await;
                     return;
@@ -236,15 +374,28 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
 
         println!("sending");
 
-        relay_msg = format_for_reply(relay_msg.clone(), event, room).await;
-        // let discord_msg = match discord::relay::relay_message(&http, relay_msg.clone()).await {
-        //     Ok(m) => m,
-        //     Err(err) => {
-        //         println!("Error: {}", err);
-        //         return;
-        //     }
-        // };
-        // chat_service::create_message(relay_msg.message, discord_msg);
+        let event_id = event.event_id.clone();
+        let mentions = intended_mentions(&raw["content"]);
+        relay_msg = format_for_reply(relay_msg.clone(), event, room.clone(), mentions).await;
+        let mentions = filter::matrix_mentions(&raw["content"]);
+        if !filter::screen(&relay_msg, mentions, false).await {
+            if CONFIG.filter.action == FilterAction::Redact {
+                if let Err(err) = room.redact(&event_id, Some("Caught by filter"), None).await {
+                    println!("Failed to redact filtered message: {}", err);
+                }
+            }
+            return;
+        }
+        let http = discord::bot::get_context().await.http;
+        let discord_msg = match discord::relay::relay_message(&http, relay_msg.clone()).await {
+            Ok(m) => m,
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        };
+        chat_service::create_message(relay_msg.message.clone(), discord_msg);
+        chat_service::set_message_author(&relay_msg.message, &relay_msg.user.id);
         // send our message to the room we found the "!party" command in
         // the last parameter is an optional transaction id which we don't
         // care about.
@@ -265,6 +416,347 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
     }
 }
 
+/// Account linking and double puppeting commands. Codes are only accepted in
+/// rooms that aren't bridged, and access tokens only in DMs with the bot, so
+/// nobody else sees them.
+async fn handle_link_command(sender: &UserId, event_id: &EventId, room: &Joined, body: &str) {
+    let bridged = CONFIG
+        .room
+        .iter()
+        .any(|m| m.matrix == room.room_id().as_str());
+    let mut args = body.split_whitespace();
+    let command = args.next().unwrap_or_default();
+    if command == "!login" {
+        // The access token stays in the room's history otherwise
+        if let Err(err) = room
+            .redact(event_id, Some("Contains an access token"), None)
+            .await
+        {
+            println!("Failed to redact !login in {}: {}", room.room_id(), err);
+        }
+    }
+    let direct = room.active_members_count() == 2
+        && super::dm::channel_for_room(room.room_id().as_str()).is_none();
+    let text = match command {
+        "!login" if bridged || !direct => {
+            "Send !login to me in a direct message with nobody else in it instead, and make a new access token as others may have seen this one".to_owned()
+        }
+        _ if bridged && command != "!unlink" && command != "!logout" => format!(
+            "Send {} to me in a direct message instead, as others can see it here",
+            command
+        ),
+        "!link" => format!(
+            "Use /link {} on Discord in the next 10 minutes to link your account",
+            link::create_code(sender.as_str())
+        ),
+        "!unlink" => match link::unlink(sender.as_str()) {
+            true => "Unlinked your Discord account".to_owned(),
+            false => "You don't have a Discord account linked".to_owned(),
+        },
+        "!login" => match args.next() {
+            Some(access_token) => match double_puppet::login(sender, access_token).await {
+                Ok(()) => {
+                    "Your Discord messages will now be sent from your Matrix account".to_owned()
+                }
+                Err(err) => format!("Couldn't use that access token: {}", err),
+            },
+            None => "Usage: !login <access token>".to_owned(),
+        },
+        _ => match double_puppet::logout(sender.as_str()) {
+            true => "Forgot your access token".to_owned(),
+            false => "You haven't given an access token".to_owned(),
+        },
+    };
+    super::relay::send_notice(room.room_id().as_str(), text).await;
+}
+
+async fn handle_privacy_command(sender: &UserId, room: &Joined, command: &str) {
+    let text = match command {
+        "!optout" => {
+            privacy::opt_out("matrix", sender.as_str());
+            "Your messages won't be bridged to Discord anymore".to_owned()
+        }
+        "!optin" => match privacy::opt_in("matrix", sender.as_str()) {
+            true => "Your messages will be bridged to Discord again".to_owned(),
+            false => "You haven't opted out".to_owned(),
+        },
+        _ => {
+            privacy::forget("matrix", sender.as_str()).await;
+            let mut text = "Removed your bridged messages from Discord and forgot you. You're opted out, send !optin to have your messages bridged again".to_owned();
+            if let Some(notice) = privacy::unattributed_notice("matrix") {
+                text = format!("{text}\n{notice}");
+            }
+            text
+        }
+    };
+    super::relay::send_notice(room.room_id().as_str(), text).await;
+}
+
+/// Starts a bridged DM with a Discord user, `!dm <discord user id>`.
+async fn handle_dm_command(sender: &UserId, room: &Joined, body: &str) {
+    let bridged = CONFIG
+        .room
+        .iter()
+        .any(|m| m.matrix == room.room_id().as_str());
+    let text = if !CONFIG.dm_users.iter().any(|user| user == sender.as_str()) {
+        "You aren't allowed to bridge DMs".to_owned()
+    } else if bridged {
+        "Send !dm to me in a direct message instead".to_owned()
+    } else {
+        match body.split_whitespace().nth(1).map(|id| id.parse::<u64>()) {
+            Some(Ok(discord_id)) => match discord::bot::dm_channel(discord_id).await {
+                Ok((user, channel_id)) => {
+                    match super::dm::get_or_create_room(&user, channel_id, sender).await {
+                        Ok(room_id) => {
+                            format!("Invited you to your DMs with {} in {}", user.tag, room_id)
+                        }
+                        Err(err) => format!("Couldn't create a room for {}: {}", user.tag, err),
+                    }
+                }
+                Err(err) => format!("Couldn't DM that Discord user: {}", err),
+            },
+            _ => "Usage: !dm <discord user id>".to_owned(),
+        }
+    };
+    super::relay::send_notice(room.room_id().as_str(), text).await;
+}
+
+/// Relays a message in a bridged DM room to the Discord user's DMs with the bot.
+async fn relay_dm_message(event: OriginalSyncRoomMessageEvent, room: Joined, channel_id: u64) {
+    // Only the allowed users' messages, not e.g the Discord user's puppet
+    if !CONFIG
+        .dm_users
+        .iter()
+        .any(|user| user == event.sender.as_str())
+    {
+        return;
+    }
+    let msg = Message {
+        service: "matrix".to_owned(),
+        server_id: "".to_owned(),
+        room_id: room.room_id().to_string(),
+        id: event.event_id.to_string(),
+    };
+    if chat_service::message_relayed(msg.clone()) {
+        return;
+    }
+
+    let relay_msg = FullMessage {
+        message: msg,
+        user: User {
+            source: "matrix".to_owned(),
+            id: event.sender.to_string(),
+            ping: format!("<@{}>", event.sender),
+            tag: event.sender.to_string(),
+            display: event.sender.to_string(),
+            avatar: None,
+        },
+        content: event.content.body().to_string(),
+        reply: None,
+        reply_preview: None,
+        timestamp: Some(event.origin_server_ts.get().into()),
+        sticker: None,
+        attachments: vec![],
+    };
+    let http = discord::bot::get_context().await.http;
+    // Edits replace the Discord copy of the original message
+    if let Some(Relation::Replacement(r)) = event.content.relates_to {
+        let mut edit = relay_msg;
+        edit.message.id = r.event_id.to_string();
+        edit.content = edit
+            .content
+            .strip_prefix("* ")
+            .unwrap_or(&edit.content)
+            .to_owned();
+        discord::relay::edit_dm_message(&http, edit).await;
+        return;
+    }
+    match discord::relay::relay_dm_message(&http, channel_id, relay_msg.clone()).await {
+        Ok(discord_msg) => {
+            chat_service::create_message(relay_msg.message.clone(), discord_msg);
+            chat_service::set_message_author(&relay_msg.message, &relay_msg.user.id);
+        }
+        Err(err) => println!("Failed to relay DM: {}", err),
+    }
+}
+
+/// Joins rooms the bot is invited to, so it can be sent commands in direct
+/// messages. Only invites to rooms the bridge uses, or from users on our
+/// homeserver or allowed to bridge DMs, are accepted.
+async fn handle_invite(event: StrippedRoomMemberEvent, room: Room, client: Client) {
+    if event.content.membership != MembershipState::Invite
+        || client.user_id() != Some(event.state_key.as_ref())
+    {
+        return;
+    }
+    let room_id = room.room_id().as_str();
+    let used = CONFIG.room.iter().any(|m| m.matrix == room_id)
+        || CONFIG
+            .policy_rooms
+            .iter()
+            .any(|policy_room| policy_room == room_id)
+        || CONFIG.filter.admin_room.as_deref() == Some(room_id)
+        || super::dm::channel_for_room(room_id).is_some();
+    let known = is_bridge_user(&event.sender)
+        || event.sender.server_name() == CONFIG.server_name.as_str()
+        || CONFIG
+            .dm_users
+            .iter()
+            .any(|user| user == event.sender.as_str());
+    if !used && !known {
+        println!("Ignoring invite to {} from {}", room_id, event.sender);
+        return;
+    }
+    if let Room::Invited(room) = room {
+        if let Err(err) = room.accept_invitation().await {
+            println!("Failed to join {}: {}", room.room_id(), err);
+        }
+    }
+}
+
+/// Commands for held messages, anyone in the admin room can use them.
+async fn handle_admin_command(body: &str) {
+    let mut args = body.split_whitespace();
+    let approved = match args.next() {
+        Some("!approve") => true,
+        Some("!reject") => false,
+        _ => return,
+    };
+    let Some(id) = args.next().and_then(|id| id.parse::<u64>().ok()) else {
+        filter::log_to_admin_room("Usage: !approve <id> or !reject <id>".to_owned()).await;
+        return;
+    };
+
+    let text = match filter::release(id, approved).await {
+        true if approved => format!("Approved message {id}"),
+        true => format!("Rejected message {id}"),
+        false => format!("No held message {id}"),
+    };
+    filter::log_to_admin_room(text).await;
+}
+
+/// Whether a Matrix user's events in a bridged room can be relayed to Discord.
+fn can_relay(sender: &UserId, room_id: &str) -> bool {
+    !chat_service::is_banned("matrix", sender.as_str(), room_id)
+        && !privacy::is_opted_out("matrix", sender.as_str())
+        && !policy::is_banned(sender)
+}
+
+async fn handle_sticker(event: OriginalSyncStickerEvent, room: Room, raw: RawEvent) {
+    if is_bridge_user(&event.sender) {
+        return;
+    }
+    let raw: serde_json::Value = serde_json::from_str(raw.get()).unwrap_or_default();
+    if raw["content"].get(double_puppet::SOURCE_KEY).is_some() {
+        return;
+    }
+    if !CONFIG
+        .room
+        .iter()
+        .any(|m| m.matrix == room.room_id().as_str())
+    {
+        return;
+    }
+    let msg = Message {
+        service: "matrix".to_owned(),
+        server_id: "".to_owned(),
+        room_id: room.room_id().to_string(),
+        id: event.event_id.to_string(),
+    };
+    if chat_service::message_relayed(msg.clone()) || !can_relay(&event.sender, &msg.room_id) {
+        return;
+    }
+    let relay_msg = FullMessage {
+        message: msg,
+        user: User {
+            source: "matrix".to_owned(),
+            id: event.sender.to_string(),
+            ping: format!("<@{}>", event.sender),
+            tag: event.sender.to_string(),
+            display: event.sender.to_string(),
+            avatar: None,
+        },
+        content: "".to_owned(),
+        reply: None,
+        reply_preview: None,
+        timestamp: Some(event.origin_server_ts.get().into()),
+        sticker: Some(Sticker {
+            name: event.content.body.clone(),
+            // Downloaded as the bot when it's sent
+            url: event.content.url.to_string(),
+            lottie: false,
+        }),
+        attachments: vec![],
+    };
+    if !filter::screen(&relay_msg, 0, false).await {
+        if let (FilterAction::Redact, Room::Joined(room)) = (&CONFIG.filter.action, &room) {
+            if let Err(err) = room
+                .redact(&event.event_id, Some("Caught by filter"), None)
+                .await
+            {
+                println!("Failed to redact filtered sticker: {}", err);
+            }
+        }
+        return;
+    }
+    let http = discord::bot::get_context().await.http;
+    match discord::relay::relay_message(&http, relay_msg.clone()).await {
+        Ok(discord_msg) => {
+            chat_service::create_message(relay_msg.message.clone(), discord_msg);
+            chat_service::set_message_author(&relay_msg.message, &relay_msg.user.id);
+        }
+        Err(err) => println!("Failed to relay sticker: {}", err),
+    }
+}
+
+async fn handle_reaction(event: OriginalSyncReactionEvent, room: Room, raw: RawEvent) {
+    if is_bridge_user(&event.sender) {
+        return;
+    }
+    let raw: serde_json::Value = serde_json::from_str(raw.get()).unwrap_or_default();
+    if raw["content"].get(double_puppet::SOURCE_KEY).is_some() {
+        return;
+    }
+    let Some(m) = CONFIG
+        .room
+        .iter()
+        .find(|m| m.matrix == room.room_id().as_str())
+    else {
+        return;
+    };
+    if !can_relay(&event.sender, &m.matrix) {
+        return;
+    }
+
+    // Emote reactions may have their name, under either key
+    let shortcode = raw["content"]["shortcode"]
+        .as_str()
+        .or(raw["content"]["com.beeper.reaction.shortcode"].as_str());
+    let annotation = event.content.relates_to;
+    let Some(reaction) =
+        discord::emoji::reaction_to_discord(&annotation.key, shortcode, m.discord_guild).await
+    else {
+        println!("No guild emoji like reaction {}", annotation.key);
+        return;
+    };
+
+    let msg = Message {
+        service: "matrix".to_owned(),
+        server_id: "".to_owned(),
+        room_id: room.room_id().to_string(),
+        id: annotation.event_id.to_string(),
+    };
+    let key = reaction.to_string();
+    if discord::relay::react(msg.clone(), reaction).await {
+        // Saved so redacting it removes it from Discord too
+        let relayed = Message {
+            id: event.event_id.to_string(),
+            ..msg.clone()
+        };
+        chat_service::save_matrix_reaction(&relayed, event.sender.as_str(), &msg, &key);
+    }
+}
+
 async fn handle_message_redact(event: OriginalSyncRoomRedactionEvent, room: Room) {
     if let Room::Joined(room) = room {
         let msg = chat_service::Message {
@@ -274,11 +766,210 @@ async fn handle_message_redact(event: OriginalSyncRoomRedactionEvent, room: Room
             id: event.redacts.to_string(),
         };
 
+        if discord::relay::remove_matrix_reaction(&msg).await {
+            return;
+        }
         discord::relay::delete_message(msg.clone()).await;
         chat_service::delete_message(msg.clone());
     }
 }
 
+fn is_bridge_user(sender: &ruma::UserId) -> bool {
+    let registration_local = (*(BOT_REGISTRATION.lock().unwrap())).clone().unwrap();
+    sender
+        .localpart()
+        .starts_with(&registration_local.sender_localpart)
+}
+
+async fn handle_room_name(event: OriginalSyncRoomNameEvent, room: Room) {
+    if is_bridge_user(&event.sender) {
+        return;
+    }
+    let Some(m) = CONFIG
+        .room
+        .iter()
+        .find(|m| m.matrix == room.room_id().to_string())
+    else {
+        return;
+    };
+    if !m.name_to_discord {
+        return;
+    }
+    let Some(name) = event.content.name else {
+        return;
+    };
+
+    let ctx = discord::bot::get_context().await;
+    if let Err(err) =
+        discord::relay::edit_channel(&ctx, m.discord, Some(name.to_string()), None).await
+    {
+        println!("Failed to set channel name: {}", err);
+    }
+}
+
+/// Bridges bans of puppets and Matrix users in bridged rooms, along with kicks
+/// to the audit log.
+async fn handle_room_member(event: OriginalSyncRoomMemberEvent, room: Room) {
+    // Bans from Discord are recorded when they're bridged
+    if is_bridge_user(&event.sender) {
+        return;
+    }
+    let Some(m) = CONFIG
+        .room
+        .iter()
+        .find(|m| m.matrix == room.room_id().to_string())
+    else {
+        return;
+    };
+
+    let discord_id = super::puppet::discord_id(&event.state_key);
+    let (service, user_id) = match &discord_id {
+        Some(discord_id) => ("discord", discord_id.clone()),
+        None => ("matrix", event.state_key.to_string()),
+    };
+    let reason = event.content.reason.clone();
+
+    match event.membership_change() {
+        MembershipChange::Banned | MembershipChange::KickedAndBanned => {
+            chat_service::set_banned(service, &user_id, &m.matrix, true);
+            chat_service::log_moderation("matrix", "ban", &user_id, &m.matrix, reason.as_deref());
+            if let Some(discord_id) = discord_id {
+                let _ = super::puppet::forget_room(event.state_key.as_str(), &m.matrix);
+                if m.ban_to_discord {
+                    ban_on_discord(m, &discord_id, reason).await;
+                }
+            }
+        }
+        MembershipChange::Unbanned => {
+            chat_service::set_banned(service, &user_id, &m.matrix, false);
+            chat_service::log_moderation("matrix", "unban", &user_id, &m.matrix, reason.as_deref());
+        }
+        MembershipChange::Kicked => {
+            chat_service::log_moderation("matrix", "kick", &user_id, &m.matrix, reason.as_deref());
+            if discord_id.is_some() {
+                let _ = super::puppet::forget_room(event.state_key.as_str(), &m.matrix);
+            }
+        }
+        _ => {}
+    }
+}
+
+async fn ban_on_discord(m: &Entry, discord_id: &str, reason: Option<String>) {
+    let Ok(discord_id) = discord_id.parse::<u64>() else {
+        return;
+    };
+    let reason = reason.unwrap_or_else(|| "Banned on Matrix".to_owned());
+    let ctx = discord::bot::get_context().await;
+    match discord::relay::ban_user(&ctx, m, discord_id, &reason).await {
+        Ok(()) => chat_service::log_moderation(
+            "discord",
+            "ban",
+            &discord_id.to_string(),
+            &m.matrix,
+            Some(&reason),
+        ),
+        Err(err) => println!("Failed to ban {} on Discord: {}", discord_id, err),
+    }
+}
+
+async fn handle_room_encryption(_event: OriginalSyncRoomEncryptionEvent, room: Room) {
+    encryption::set_encrypted(room.room_id());
+    let bridged = CONFIG
+        .room
+        .iter()
+        .any(|m| m.matrix == room.room_id().as_str());
+    if bridged || super::dm::channel_for_room(room.room_id().as_str()).is_some() {
+        encryption::warn(room.room_id()).await;
+    }
+}
+
+async fn handle_room_topic(event: OriginalSyncRoomTopicEvent, room: Room) {
+    if is_bridge_user(&event.sender) {
+        return;
+    }
+    let Some(m) = CONFIG
+        .room
+        .iter()
+        .find(|m| m.matrix == room.room_id().to_string())
+    else {
+        return;
+    };
+    if !m.topic_to_discord {
+        return;
+    }
+
+    let ctx = discord::bot::get_context().await;
+    if let Err(err) =
+        discord::relay::edit_channel(&ctx, m.discord, None, Some(event.content.topic)).await
+    {
+        println!("Failed to set channel topic: {}", err);
+    }
+}
+
+/// Posts a digest of recent messages to Discord the first time a room is bridged.
+async fn backfill_to_discord(user: Client, m: Entry) {
+    let backfilled_key = format!("matrix_backfilled_{}", m.matrix);
+    if m.backfill_to_discord == 0 || chat_service::get_state(&backfilled_key).is_some() {
+        return;
+    }
+    // The room was bridged before backfilling was turned on
+    if chat_service::room_has_messages(&m.matrix) {
+        chat_service::set_state(&backfilled_key, "true");
+        return;
+    }
+
+    let id = match RoomId::parse(&m.matrix) {
+        Ok(id) => id,
+        Err(err) => {
+            println!("Can't backfill {}, invalid room id: {}", m.matrix, err);
+            return;
+        }
+    };
+    let Some(room) = user.get_joined_room(&id) else {
+        return;
+    };
+
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::new_saturating(m.backfill_to_discord as u64);
+    let messages = match room.messages(options).await {
+        Ok(messages) => messages,
+        Err(err) => {
+            println!("Failed to get history of {}: {}", m.matrix, err);
+            return;
+        }
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    // Messages are returned newest first
+    for event in messages.chunk.iter().rev() {
+        let v: serde_json::Value = serde_json::from_str(&event.event.json().to_string()).unwrap();
+        if v["type"] != "m.room.message" {
+            continue;
+        }
+        let Ok(sender) = UserId::parse(v["sender"].as_str().unwrap_or_default()) else {
+            continue;
+        };
+        if is_bridge_user(&sender) {
+            continue;
+        }
+        let Some(body) = v["content"]["body"].as_str() else {
+            continue;
+        };
+        lines.push(format!(
+            "**{}**: {}",
+            sender,
+            strip_reply(body.to_owned()).trim_end()
+        ));
+    }
+
+    let http = discord::bot::get_context().await.http;
+    if let Err(err) = discord::relay::send_digest(&http, &m, lines).await {
+        println!("Failed to send digest of {}: {}", m.matrix, err);
+        return;
+    }
+    chat_service::set_state(&backfilled_key, "true");
+}
+
 pub async fn start_bot() -> Result<()> {
     // Currently this causes a stack overflow on windows, stack size has been increased during compilation as a temporary fix.
     // TODO: Find better fix
@@ -336,11 +1027,9 @@ pub async fn start_bot() -> Result<()> {
     }
     println!("Created user!");
 
-    let user = appservice_local
-        .as_ref()
-        .unwrap()
-        .user(Some(&main_bot_name))
-        .await?;
+    let user = encryption::user(appservice_local.as_ref().unwrap(), &main_bot_name)
+        .await
+        .expect("Failed to get bot client");
     let changed_name = user
         .account()
         .set_display_name(Some("Discord Relay"))
@@ -353,12 +1042,26 @@ pub async fn start_bot() -> Result<()> {
 
     for mroom in CONFIG.room.iter() {
         let roomid = mroom.matrix.clone();
-        let id: Box<RoomId> = RoomId::parse_box(roomid.as_ref()).unwrap();
+        let Ok(id) = RoomId::parse_box(roomid.as_ref()) else {
+            println!("Invalid room id {}", roomid);
+            continue;
+        };
         let _ = user.join_room_by_id(id.as_ref()).await;
     }
 
     println!("Joined rooms");
 
+    for policy_room in CONFIG.policy_rooms.iter() {
+        if let Ok(id) = RoomId::parse_box(policy_room.as_ref()) {
+            let _ = user.join_room_by_id(id.as_ref()).await;
+        }
+        policy::load_rules(&user, policy_room).await;
+    }
+
+    for mroom in CONFIG.room.iter() {
+        tokio::spawn(backfill_to_discord(user.clone(), mroom.clone()));
+    }
+
     // This runs the code in a seperate scope, so that it will not keep the mutexes locked.
     {
         *(BOT_REGISTRATION
@@ -370,16 +1073,40 @@ pub async fn start_bot() -> Result<()> {
         *(BOT_CLIENT.lock().expect("Bot client is poisoned")) = Some(user.clone());
     }
 
+    for mroom in CONFIG.room.iter() {
+        if let Ok(id) = RoomId::parse_box(mroom.matrix.as_ref()) {
+            if encryption::is_encrypted(id.as_ref()).await {
+                encryption::warn(id.as_ref()).await;
+            }
+        }
+    }
+
     println!("Syncing");
 
-    // Sync to prevent handling old messages
-    let syncres: SyncResponse = user.sync_once(SyncSettings::default()).await.unwrap();
+    // Resume from where we left off so messages sent while the relay was down
+    // are still relayed. On the first run there's nothing to resume from, so
+    // we sync once to prevent handling old messages.
+    let sync_token = match chat_service::get_state(SYNC_TOKEN_KEY) {
+        Some(token) => token,
+        None => {
+            let syncres: SyncResponse = user.sync_once(SyncSettings::default()).await.unwrap();
+            chat_service::set_state(SYNC_TOKEN_KEY, &syncres.next_batch);
+            syncres.next_batch
+        }
+    };
 
     println!("Registering events");
 
     user.add_event_handler_context(appservice_local.clone());
     user.add_event_handler(handle_room_message);
     user.add_event_handler(handle_message_redact);
+    user.add_event_handler(handle_reaction);
+    user.add_event_handler(handle_sticker);
+    user.add_event_handler(handle_room_name);
+    user.add_event_handler(handle_room_topic);
+    user.add_event_handler(handle_room_member);
+    user.add_event_handler(handle_room_encryption);
+    user.add_event_handler(handle_invite);
 
     print!("Splitting");
 
@@ -392,7 +1119,7 @@ pub async fn start_bot() -> Result<()> {
 
     future::join(
         run_appservice(appservice_local.clone().unwrap(), host),
-        sync_bot(user, syncres),
+        sync_bot(user, sync_token),
     )
     .await
     .0
@@ -402,15 +1129,37 @@ pub async fn start_bot() -> Result<()> {
     Ok(())
 }
 
-pub async fn run_appservice(appservice: AppService, host: Vec<&str>) -> Result<()> {
-    appservice
-        .run(host[0].to_owned(), host[1].parse::<u16>().unwrap())
+/// Serves the appservice API along with the media proxy.
+pub async fn run_appservice(appservice: AppService, host: Vec<&str>) -> anyhow::Result<()> {
+    let app = axum::Router::new()
+        .route("/media/:server_name/:media_id", get(super::media::proxy))
+        .merge(appservice.service());
+    let address: SocketAddr = format!("{}:{}", host[0], host[1]).parse()?;
+    axum::Server::bind(&address)
+        .serve(app.into_make_service())
         .await?;
     Ok(())
 }
 
-pub async fn sync_bot(user: Client, syncres: SyncResponse) -> Result<()> {
-    let settings = SyncSettings::default().token(syncres.next_batch);
-    user.sync(settings).await.expect("Error during sync!");
+pub async fn sync_bot(user: Client, sync_token: String) -> Result<()> {
+    let settings = SyncSettings::default().token(sync_token);
+    // Event handlers have already run by the time the callback is called, so
+    // the token is only saved once the events it covers have been relayed.
+    user.sync_with_callback(settings, |response| {
+        let user = user.clone();
+        async move {
+            // Policy rooms don't see much activity, so their rules are just
+            // reloaded whenever anything happens in them
+            for room_id in response.rooms.join.keys() {
+                if policy::is_policy_room(room_id.as_str()) {
+                    policy::load_rules(&user, room_id.as_str()).await;
+                }
+            }
+            chat_service::set_state(SYNC_TOKEN_KEY, &response.next_batch);
+            LoopCtrl::Continue
+        }
+    })
+    .await
+    .expect("Error during sync!");
     return Ok(());
 }
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use ruma::{
+    api::client::room::create_room::{self, v3::RoomPreset},
+    events::room::member::MembershipState,
+    OwnedRoomId, RoomId, UserId,
+};
+
+use crate::{chat_service::User, DATABASE};
+
+use super::bot::BOT_CLIENT;
+use super::puppet;
+
+/// The Matrix room a Discord DM channel is bridged to.
+pub fn room_for_channel(channel_id: &str) -> Option<String> {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT room_id FROM dm_rooms WHERE channel_id=?",
+            [channel_id],
+            |row| row.get(0),
+        )
+        .ok()
+}
+
+/// The Discord DM channel a Matrix room is bridged to.
+pub fn channel_for_room(room_id: &str) -> Option<u64> {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT channel_id FROM dm_rooms WHERE room_id=?",
+            [room_id],
+            |row| row.get::<usize, String>(0),
+        )
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Gets the room a Discord user's DMs with the bot are bridged to, creating it
+/// with their puppet the first time. Each Discord user has one room, Matrix
+/// users are invited to it when they start a DM with someone who already has one.
+pub async fn get_or_create_room(
+    user: &User,
+    channel_id: u64,
+    invite: &UserId,
+) -> Result<OwnedRoomId> {
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned")))
+        .clone()
+        .unwrap();
+
+    let existing = DATABASE
+        .lock()
+        .query_row(
+            "SELECT room_id FROM dm_rooms WHERE discord_user=?",
+            [&user.id],
+            |row| row.get::<usize, String>(0),
+        )
+        .ok();
+    if let Some(room_id) = existing.and_then(|room_id| RoomId::parse(room_id).ok()) {
+        if let Some(room) = client_local.get_joined_room(&room_id) {
+            // Members who left or were kicked are still returned, so check they're in it
+            let member = room.get_member_no_sync(invite).await.ok().flatten();
+            let in_room = member.map_or(false, |member| {
+                matches!(
+                    member.membership(),
+                    MembershipState::Join | MembershipState::Invite
+                )
+            });
+            if !in_room {
+                room.invite_user_by_id(invite).await?;
+            }
+        }
+        return Ok(room_id);
+    }
+
+    let puppet = puppet::get_puppet(user.id.clone()).await;
+    let mut request = create_room::v3::Request::new();
+    request.invite = vec![
+        invite.to_owned(),
+        client_local.user_id().unwrap().to_owned(),
+    ];
+    request.is_direct = true;
+    request.preset = Some(RoomPreset::TrustedPrivateChat);
+    let room_id = puppet.send(request, None).await?.room_id;
+
+    client_local.join_room_by_id(&room_id).await?;
+    let room = puppet::get_room(&puppet, &room_id).await?;
+    puppet::set_room_profile(&puppet, &room, user).await;
+
+    DATABASE.lock().execute(
+        "INSERT OR REPLACE INTO dm_rooms (discord_user, channel_id, room_id) VALUES (?, ?, ?)",
+        [&user.id, &channel_id.to_string(), room_id.as_str()],
+    )?;
+    println!("Bridged DMs with {} to {}", user.tag, room_id);
+    Ok(room_id)
+}
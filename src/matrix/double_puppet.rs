@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use matrix_sdk::{config::RequestConfig, room::Joined, Client, Session};
+use ruma::{
+    api::client::message::send_message_event,
+    events::{
+        room::member::MembershipState, AnyMessageLikeEventContent, EventContent,
+        MessageLikeEventContent,
+    },
+    serde::Raw,
+    OwnedDeviceId, OwnedEventId, OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::Deserialize;
+
+use crate::{link, CONFIG, DATABASE};
+
+use super::bot::BOT_CLIENT;
+use super::encryption;
+
+/// Added to the content of events sent from users' real accounts, so the
+/// bridge knows not to relay them back to Discord.
+pub const SOURCE_KEY: &str = "fi.mau.double_puppet_source";
+const SOURCE: &str = "matrix_discord_relay";
+
+#[derive(Debug, Deserialize)]
+struct WhoAmI {
+    user_id: OwnedUserId,
+    device_id: Option<OwnedDeviceId>,
+}
+
+lazy_static! {
+    static ref CLIENTS: parking_lot::Mutex<HashMap<String, Client>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+async fn build_client(session: Session, appservice: bool) -> Result<Client> {
+    let mut builder = Client::builder().homeserver_url(&CONFIG.homeserver_url);
+    if appservice {
+        // The as_token can act as anyone, so requests say who they're for
+        builder = builder
+            .appservice_mode()
+            .request_config(RequestConfig::new().assert_identity());
+    }
+    let client = builder.build().await?;
+    client.restore_login(session).await?;
+    Ok(client)
+}
+
+/// Saves an access token a user gave to have their Discord messages sent from
+/// their Matrix account, after checking it's theirs.
+pub async fn login(matrix_id: &UserId, access_token: &str) -> Result<()> {
+    let whoami = reqwest::Client::new()
+        .get(format!(
+            "{}/_matrix/client/v3/account/whoami",
+            CONFIG.homeserver_url.trim_end_matches('/')
+        ))
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<WhoAmI>()
+        .await?;
+    if whoami.user_id != matrix_id {
+        bail!("That access token belongs to {}", whoami.user_id);
+    }
+    let Some(device_id) = whoami.device_id else {
+        bail!("That access token doesn't have a device");
+    };
+
+    DATABASE.lock().execute(
+        "INSERT OR REPLACE INTO double_puppets (matrix_id, access_token, device_id) VALUES (?, ?, ?)",
+        [matrix_id.as_str(), access_token, device_id.as_str()],
+    )?;
+    CLIENTS.lock().remove(matrix_id.as_str());
+    Ok(())
+}
+
+/// Forgets a user's access token, returning whether they had given one.
+pub fn logout(matrix_id: &str) -> bool {
+    CLIENTS.lock().remove(matrix_id);
+    DATABASE
+        .lock()
+        .execute("DELETE FROM double_puppets WHERE matrix_id=?", [matrix_id])
+        .map_or(false, |deleted| deleted > 0)
+}
+
+/// A client for a user's real account, either from an access token they gave
+/// or through the double puppeting appservice for users on our homeserver.
+async fn get_client(matrix_id: &UserId) -> Option<Client> {
+    if let Some(client) = CLIENTS.lock().get(matrix_id.as_str()) {
+        return Some(client.clone());
+    }
+
+    let saved = DATABASE
+        .lock()
+        .query_row(
+            "SELECT access_token, device_id FROM double_puppets WHERE matrix_id=?",
+            [matrix_id.as_str()],
+            |row| Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?)),
+        )
+        .ok();
+    let (session, appservice) = match (saved, &CONFIG.double_puppet_as_token) {
+        (Some((access_token, device_id)), _) => (
+            Session {
+                access_token,
+                refresh_token: None,
+                user_id: matrix_id.to_owned(),
+                device_id: device_id.into(),
+            },
+            false,
+        ),
+        (None, Some(as_token)) if matrix_id.server_name() == CONFIG.server_name.as_str() => (
+            Session {
+                access_token: as_token.clone(),
+                refresh_token: None,
+                user_id: matrix_id.to_owned(),
+                device_id: "DISCORD_RELAY".into(),
+            },
+            true,
+        ),
+        _ => return None,
+    };
+
+    match build_client(session, appservice).await {
+        Ok(client) => {
+            CLIENTS.lock().insert(matrix_id.to_string(), client.clone());
+            Some(client)
+        }
+        Err(err) => {
+            println!("Failed to log in as {}: {}", matrix_id, err);
+            None
+        }
+    }
+}
+
+/// The real account a Discord user's events should be sent from in a room,
+/// None if they haven't linked one or it isn't in the room. The bridge never
+/// joins real accounts to rooms themselves.
+pub async fn for_room(discord_id: &str, room_id: &RoomId) -> Option<(Client, Joined)> {
+    let matrix_id = UserId::parse(link::linked_matrix_id(discord_id)?).ok()?;
+
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let bot_room = client_local?.get_joined_room(room_id)?;
+    let member = bot_room.get_member_no_sync(&matrix_id).await.ok()??;
+    if *member.membership() != MembershipState::Join {
+        return None;
+    }
+
+    let client = get_client(&matrix_id).await?;
+    // The client doesn't sync, so it only knows about the room after joining,
+    // which does nothing as it's already in it
+    if client.get_joined_room(room_id).is_none() {
+        if let Err(err) = client.join_room_by_id(room_id).await {
+            println!("{} couldn't get {}: {}", matrix_id, room_id, err);
+            return None;
+        }
+    }
+    Some((client.clone(), client.get_joined_room(room_id)?))
+}
+
+/// Sends an event from a user's real account, marked so it isn't relayed back.
+/// Real accounts have no keys at the bridge, so encrypted rooms are refused.
+pub async fn send(
+    client: &Client,
+    room: &Joined,
+    content: impl MessageLikeEventContent,
+) -> Result<OwnedEventId> {
+    if encryption::is_encrypted(room.room_id()).await {
+        bail!("{} is encrypted", room.room_id());
+    }
+    let mut json = serde_json::to_value(&content)?;
+    json[SOURCE_KEY] = SOURCE.into();
+
+    let request = send_message_event::v3::Request::new_raw(
+        room.room_id().to_owned(),
+        TransactionId::new(),
+        content.event_type(),
+        Raw::new(&json)?.cast::<AnyMessageLikeEventContent>(),
+    );
+    Ok(client.send(request, None).await?.event_id)
+}
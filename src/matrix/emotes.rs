@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use matrix_sdk::{
+    media::{MediaFormat, MediaRequest},
+    Client,
+};
+use regex::{Captures, Regex};
+use ruma::{
+    events::{
+        room::{ImageInfo, MediaSource},
+        sticker::StickerEventContent,
+    },
+    OwnedMxcUri, UInt,
+};
+use serde_json::json;
+
+use crate::{
+    chat_service::{self, Sticker},
+    CONFIG, DATABASE,
+};
+
+use super::bot::BOT_CLIENT;
+use super::{media, relay};
+
+/// State key of the pack of Discord emoji in each bridged room
+const PACK_STATE_KEY: &str = "discord";
+
+lazy_static! {
+    static ref DISCORD_EMOJI: Regex = Regex::new(r"<(a?):(\w+):(\d+)>").unwrap();
+    /// Emoji in a message after it's been rendered to HTML
+    static ref ESCAPED_EMOJI: Regex = Regex::new(r"&lt;(a?):(\w+):(\d+)&gt;").unwrap();
+}
+
+/// A Discord custom emoji.
+#[derive(Debug, Clone)]
+pub struct Emoji {
+    pub id: String,
+    pub name: String,
+    pub animated: bool,
+}
+
+impl Emoji {
+    fn url(&self) -> String {
+        let extension = if self.animated { "gif" } else { "png" };
+        format!(
+            "https://cdn.discordapp.com/emojis/{}.{}",
+            self.id, extension
+        )
+    }
+}
+
+/// Uploads a Discord emoji to the media repo, once.
+pub async fn emoji_mxc(emoji: &Emoji) -> Option<OwnedMxcUri> {
+    let cached: Option<String> = DATABASE
+        .lock()
+        .query_row(
+            "SELECT mxc_uri FROM emoji WHERE discord_id=?",
+            [&emoji.id],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(cached) = cached {
+        return Some(cached.into());
+    }
+
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let mxc_uri = media::upload_url(&client_local?, &emoji.url()).await?;
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO emoji (discord_id, name, mxc_uri) VALUES (?, ?, ?)",
+            [&emoji.id, &emoji.name, mxc_uri.as_str()],
+        )
+        .expect("Failed to save emoji to database!");
+    Some(mxc_uri)
+}
+
+/// Publishes a guild's emoji as an image pack in the rooms bridged to it, so
+/// Matrix users can use them too.
+pub async fn sync_packs(guild_id: u64, emojis: Vec<Emoji>) {
+    // Guilds are sent when the Discord bot connects, which can be before the Matrix bot is ready
+    while BOT_CLIENT.lock().expect("Bot client is poisoned").is_none() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut images = BTreeMap::new();
+    for emoji in emojis.iter() {
+        if let Some(mxc_uri) = emoji_mxc(emoji).await {
+            images.insert(
+                emoji.name.clone(),
+                json!({ "url": mxc_uri, "body": format!(":{}:", emoji.name) }),
+            );
+        }
+    }
+    let content = json!({
+        "images": images,
+        "pack": { "display_name": "Discord", "usage": ["emoticon"] },
+    });
+
+    for room in CONFIG
+        .room
+        .iter()
+        .filter(|room| room.discord_guild == guild_id)
+    {
+        let state_key = format!("emote_pack_{}", room.matrix);
+        if chat_service::get_state(&state_key) == Some(content.to_string()) {
+            continue;
+        }
+        let Some(joined) = relay::get_room_as_bot(&room.matrix) else {
+            continue;
+        };
+        match joined
+            .send_state_event_raw(content.clone(), "im.ponies.room_emotes", PACK_STATE_KEY)
+            .await
+        {
+            Ok(_) => chat_service::set_state(&state_key, &content.to_string()),
+            Err(err) => println!("Failed to set emote pack of {}: {}", room.matrix, err),
+        }
+    }
+}
+
+/// Converts a Lottie sticker to a GIF with the configured converter, and
+/// uploads it, once.
+async fn lottie_mxc(client: &Client, sticker: &Sticker) -> Option<OwnedMxcUri> {
+    let converter = CONFIG.lottie_converter.as_ref()?;
+    let cached: Option<String> = DATABASE
+        .lock()
+        .query_row(
+            "SELECT mxc_uri FROM lottie_stickers WHERE discord_url=?",
+            [&sticker.url],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(cached) = cached {
+        return Some(cached.into());
+    }
+
+    let animation = reqwest::get(&sticker.url).await.ok()?.bytes().await.ok()?;
+    let file_name = sticker.url.rsplit('/').next().unwrap_or("sticker.json");
+    let input = std::env::temp_dir().join(file_name);
+    let output = input.with_extension("gif");
+    std::fs::write(&input, animation).ok()?;
+
+    let command = converter
+        .replace("{input}", &input.to_string_lossy())
+        .replace("{output}", &output.to_string_lossy());
+    let status = tokio::task::spawn_blocking(move || {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()
+    })
+    .await;
+    let gif = match status {
+        Ok(Ok(status)) if status.success() => std::fs::read(&output).ok(),
+        _ => {
+            println!("Failed to convert sticker {}", sticker.url);
+            None
+        }
+    };
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+
+    let uploaded = match client.media().upload(&mime::IMAGE_GIF, gif?).await {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            println!("Failed to upload sticker {}: {}", sticker.url, err);
+            return None;
+        }
+    };
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO lottie_stickers (discord_url, mxc_uri) VALUES (?, ?)",
+            (&sticker.url, uploaded.content_uri.as_str()),
+        )
+        .expect("Failed to save sticker to database!");
+    Some(uploaded.content_uri)
+}
+
+/// Uploads a Discord sticker, once.
+async fn sticker_mxc(client: &Client, sticker: &Sticker) -> Option<OwnedMxcUri> {
+    let cached: Option<String> = DATABASE
+        .lock()
+        .query_row(
+            "SELECT mxc_uri FROM stickers WHERE discord_url=?",
+            [&sticker.url],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(cached) = cached {
+        return Some(cached.into());
+    }
+
+    let mxc_uri = media::upload_url(client, &sticker.url).await?;
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO stickers (discord_url, mxc_uri) VALUES (?, ?)",
+            (&sticker.url, mxc_uri.as_str()),
+        )
+        .expect("Failed to save sticker to database!");
+    Some(mxc_uri)
+}
+
+/// Uploads a Discord sticker and makes an `m.sticker` event for it, None if
+/// it can't be shown on Matrix.
+pub async fn sticker_content(sticker: &Sticker) -> Option<StickerEventContent> {
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let client = client_local?;
+    let (mxc_uri, mimetype) = if sticker.lottie {
+        (lottie_mxc(&client, sticker).await?, "image/gif")
+    } else if sticker.url.ends_with(".gif") {
+        (sticker_mxc(&client, sticker).await?, "image/gif")
+    } else {
+        (sticker_mxc(&client, sticker).await?, "image/png")
+    };
+
+    // Discord shows stickers at this size
+    let mut info = ImageInfo::new();
+    info.height = Some(UInt::from(160u32));
+    info.width = Some(UInt::from(160u32));
+    info.mimetype = Some(mimetype.to_owned());
+    Some(StickerEventContent::new(
+        sticker.name.clone(),
+        info,
+        mxc_uri,
+    ))
+}
+
+/// Downloads Matrix media as the bot.
+pub async fn download(mxc_uri: &str) -> Option<Vec<u8>> {
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let request = MediaRequest {
+        source: MediaSource::Plain(mxc_uri.into()),
+        format: MediaFormat::File,
+    };
+    match client_local?
+        .media()
+        .get_media_content(&request, true)
+        .await
+    {
+        Ok(data) => Some(data),
+        Err(err) => {
+            println!("Failed to download {}: {}", mxc_uri, err);
+            None
+        }
+    }
+}
+
+/// Replaces Discord emoji in a message body with their names.
+pub fn emoji_to_text(body: &str) -> String {
+    DISCORD_EMOJI.replace_all(body, ":$2:").into_owned()
+}
+
+/// Replaces Discord emoji in a message rendered to HTML with inline images.
+pub async fn emoji_to_html(html: &str) -> String {
+    let emojis: Vec<Emoji> = ESCAPED_EMOJI
+        .captures_iter(html)
+        .map(|captures| Emoji {
+            id: captures[3].to_owned(),
+            name: captures[2].to_owned(),
+            animated: &captures[1] == "a",
+        })
+        .collect();
+    let mut uploaded = Vec::new();
+    for emoji in emojis {
+        let mxc_uri = emoji_mxc(&emoji).await;
+        uploaded.push((emoji.id, mxc_uri));
+    }
+    emoji_images(html, &uploaded)
+}
+
+/// Replaces Discord emoji in HTML with inline images of their uploads, or
+/// their names if they weren't uploaded.
+pub fn emoji_images(html: &str, uploaded: &[(String, Option<OwnedMxcUri>)]) -> String {
+    ESCAPED_EMOJI
+        .replace_all(html, |captures: &Captures| {
+            let mxc_uri = uploaded
+                .iter()
+                .find(|(id, _)| *id == captures[3])
+                .and_then(|(_, mxc_uri)| mxc_uri.clone());
+            match mxc_uri {
+                Some(mxc_uri) => format!(
+                    "<img data-mx-emoticon src=\"{}\" alt=\":{name}:\" title=\":{name}:\" height=\"32\">",
+                    mxc_uri,
+                    name = &captures[2]
+                ),
+                None => format!(":{}:", &captures[2]),
+            }
+        })
+        .into_owned()
+}
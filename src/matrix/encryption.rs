@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use matrix_sdk::{config::SyncSettings, Client, Session};
+use matrix_sdk_appservice::AppService;
+use ruma::{
+    api::client::{
+        error::ErrorKind, filter::FilterDefinition, state::get_state_events_for_key,
+        sync::sync_events::v3::Filter,
+    },
+    events::StateEventType,
+    OwnedDeviceId, RoomId, UInt,
+};
+
+use crate::{chat_service, CONFIG, DATABASE};
+
+use super::bot::BOT_CLIENT;
+
+lazy_static! {
+    static ref CLIENTS: parking_lot::Mutex<HashMap<String, Client>> =
+        parking_lot::Mutex::new(HashMap::new());
+    /// Whether rooms are encrypted, rooms can't stop being encrypted so this
+    /// only has to be updated when one starts being
+    static ref ENCRYPTED: parking_lot::Mutex<HashMap<String, bool>> =
+        parking_lot::Mutex::new(HashMap::new());
+    /// Where users last synced rooms up to
+    static ref SYNCED: parking_lot::Mutex<HashMap<(String, String), String>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+/// Gets a client for an appservice user. With encryption enabled it's logged
+/// in to its own device, with a crypto store in the data dir, so it can read
+/// and send in encrypted rooms. The user has to be registered first.
+pub async fn user(appservice: &AppService, localpart: &str) -> Result<Client> {
+    if !CONFIG.encryption {
+        return Ok(appservice.user(Some(localpart)).await?);
+    }
+    if let Some(client) = CLIENTS.lock().get(localpart) {
+        return Ok(client.clone());
+    }
+
+    // The device has to stay the same as the crypto store, so its id is kept.
+    // Access tokens aren't, the appservice can log in again whenever
+    let device_key = format!("matrix_device_{localpart}");
+    let device_id = chat_service::get_state(&device_key)
+        .or_else(|| legacy_device_id(localpart))
+        .map(OwnedDeviceId::from);
+    let store_path = Path::new(&CONFIG.data_dir).join("crypto").join(localpart);
+    let client = appservice
+        .user_builder(localpart)
+        .client_builder(Client::builder().sled_store(store_path, None))
+        .device_id(device_id)
+        .login()
+        .build()
+        .await?;
+
+    if let Some(device_id) = client.device_id() {
+        chat_service::set_state(&device_key, device_id.as_str());
+    }
+    CLIENTS.lock().insert(localpart.to_owned(), client.clone());
+    Ok(client)
+}
+
+/// The device of a user from when whole sessions were saved, removing the
+/// session so its access token isn't kept.
+fn legacy_device_id(localpart: &str) -> Option<String> {
+    let session_key = format!("matrix_session_{localpart}");
+    let session = chat_service::get_state(&session_key)?;
+    DATABASE
+        .lock()
+        .execute("DELETE FROM bridge_state WHERE key=?", [&session_key])
+        .ok();
+    serde_json::from_str::<Session>(&session)
+        .ok()
+        .map(|session| session.device_id.to_string())
+}
+
+/// Whether a room is encrypted, according to its state.
+pub async fn is_encrypted(room_id: &RoomId) -> bool {
+    if let Some(encrypted) = ENCRYPTED.lock().get(room_id.as_str()) {
+        return *encrypted;
+    }
+
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let Some(client) = client_local else {
+        return false;
+    };
+    let request = get_state_events_for_key::v3::Request::new(
+        room_id.to_owned(),
+        StateEventType::RoomEncryption,
+        "".to_owned(),
+    );
+    let encrypted = match client.send(request, None).await {
+        Ok(_) => true,
+        Err(err) if err.client_api_error_kind() == Some(&ErrorKind::NotFound) => false,
+        Err(err) => {
+            println!("Failed to get encryption of {}: {}", room_id, err);
+            return false;
+        }
+    };
+    ENCRYPTED.lock().insert(room_id.to_string(), encrypted);
+    encrypted
+}
+
+pub fn set_encrypted(room_id: &RoomId) {
+    ENCRYPTED.lock().insert(room_id.to_string(), true);
+}
+
+/// Syncs an encrypted room for a client that doesn't otherwise sync, e.g a
+/// puppet, so it knows the room is encrypted and who to share keys with. Done
+/// before every send, picking up from the last, as members come and go.
+pub async fn prepare(client: &Client, room_id: &RoomId) -> Result<()> {
+    let key = (
+        client
+            .user_id()
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        room_id.to_string(),
+    );
+
+    let mut filter = FilterDefinition::default();
+    filter.room.rooms = Some(vec![room_id.to_owned()]);
+    filter.room.timeline.limit = Some(UInt::MIN);
+    let settings = SyncSettings::default().filter(Filter::FilterDefinition(filter));
+    // Tokens are per room as each sync only covers one, so the first has to be a full one
+    let last = SYNCED.lock().get(&key).cloned();
+    let settings = match last {
+        Some(token) => settings.token(token),
+        None => settings.full_state(true),
+    };
+    let response = client.sync_once(settings).await?;
+    SYNCED.lock().insert(key, response.next_batch);
+    Ok(())
+}
+
+/// Warns a bridged room that it's encrypted, once. Messages in it are
+/// decrypted at the bridge, or without encryption enabled aren't bridged at all.
+pub async fn warn(room_id: &RoomId) {
+    if !CONFIG.encryption {
+        println!(
+            "{} is encrypted, it can't be bridged without enabling encryption in the config",
+            room_id
+        );
+        return;
+    }
+
+    let state_key = format!("encryption_warned_{room_id}");
+    if chat_service::get_state(&state_key).is_some() {
+        return;
+    }
+    super::relay::send_notice(
+        room_id.as_str(),
+        "This room is encrypted. Messages are decrypted by the Discord bridge to relay them, so they can be read on the bridge's server and on Discord".to_owned(),
+    )
+    .await;
+    chat_service::set_state(&state_key, "1");
+}
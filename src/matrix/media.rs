@@ -0,0 +1,113 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+};
+use matrix_sdk::Client;
+use ruma::{MxcUri, OwnedMxcUri};
+
+use crate::{CONFIG, DATABASE};
+
+use super::bot::BOT_REGISTRATION;
+
+/// Downloads a file from Discord and uploads it to the media repo.
+pub async fn upload_url(client: &Client, url: &str) -> Option<OwnedMxcUri> {
+    let res = match reqwest::get(url).await {
+        Ok(res) => res,
+        Err(err) => {
+            println!("Failed to download {}: {}", url, err);
+            return None;
+        }
+    };
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .unwrap_or(mime::IMAGE_PNG);
+    let data = res.bytes().await.ok()?.to_vec();
+
+    match client.media().upload(&content_type, data).await {
+        Ok(uploaded) => Some(uploaded.content_uri),
+        Err(err) => {
+            println!("Failed to upload {}: {}", url, err);
+            None
+        }
+    }
+}
+
+/// Public link to Matrix media, for showing it on Discord. Served by the
+/// bridge, which downloads it as the bot, so only media the bridge linked to
+/// is served. None without a public URL for the bridge.
+pub fn public_url(mxc_uri: &str) -> Option<String> {
+    let base = CONFIG.media_url.as_ref()?;
+    let mxc_uri: &MxcUri = mxc_uri.into();
+    let (server_name, media_id) = mxc_uri.parts().ok()?;
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR IGNORE INTO linked_media (mxc_uri) VALUES (?)",
+            [mxc_uri.as_str()],
+        )
+        .ok()?;
+    Some(format!(
+        "{}/media/{}/{}",
+        base.trim_end_matches('/'),
+        server_name,
+        media_id
+    ))
+}
+
+fn is_linked(mxc_uri: &str) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM linked_media WHERE mxc_uri=?)",
+            [mxc_uri],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+/// Serves media linked to with public_url.
+pub async fn proxy(
+    Path((server_name, media_id)): Path<(String, String)>,
+) -> Result<([(header::HeaderName, String); 1], Vec<u8>), StatusCode> {
+    if !is_linked(&format!("mxc://{server_name}/{media_id}")) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let registration_local = (*(BOT_REGISTRATION.lock().unwrap())).clone();
+    let Some(registration) = registration_local else {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
+
+    // Homeservers with authenticated media only serve it from the first
+    let homeserver_url = CONFIG.homeserver_url.trim_end_matches('/');
+    let urls = [
+        format!("{homeserver_url}/_matrix/client/v1/media/download/{server_name}/{media_id}"),
+        format!("{homeserver_url}/_matrix/media/v3/download/{server_name}/{media_id}"),
+    ];
+    for url in urls {
+        let res = match reqwest::Client::new()
+            .get(&url)
+            .bearer_auth(&registration.as_token)
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_success() => res,
+            Ok(_) => continue,
+            Err(err) => {
+                println!("Failed to download {}: {}", url, err);
+                continue;
+            }
+        };
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        let data = res.bytes().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+        return Ok(([(header::CONTENT_TYPE, content_type)], data.to_vec()));
+    }
+    Err(StatusCode::BAD_GATEWAY)
+}
@@ -1,2 +1,9 @@
 pub mod bot;
+pub mod dm;
+pub mod double_puppet;
+pub mod emotes;
+pub mod encryption;
+pub mod media;
+pub mod policy;
+pub mod puppet;
 pub mod relay;
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use matrix_sdk::Client;
+use ruma::{api::client::state::get_state_events, RoomId, UserId};
+
+use crate::CONFIG;
+
+#[derive(Debug, Clone, PartialEq)]
+enum PolicyKind {
+    User,
+    Server,
+}
+
+#[derive(Debug, Clone)]
+struct PolicyRule {
+    kind: PolicyKind,
+    /// Glob of the user id or server name the rule applies to
+    entity: String,
+}
+
+lazy_static! {
+    /// Ban rules of each policy room, replaced whenever the room changes
+    static ref RULES: parking_lot::Mutex<HashMap<String, Vec<PolicyRule>>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
+
+/// Matches `text` against a policy glob, where * matches any number of
+/// characters and ? matches one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to go back to when a * has to match more characters
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn policy_kind(event_type: &str) -> Option<PolicyKind> {
+    // Older policy lists still use Mjolnir's event types
+    match event_type {
+        "m.policy.rule.user" | "org.matrix.mjolnir.rule.user" => Some(PolicyKind::User),
+        "m.policy.rule.server" | "org.matrix.mjolnir.rule.server" => Some(PolicyKind::Server),
+        _ => None,
+    }
+}
+
+pub fn is_policy_room(room_id: &str) -> bool {
+    CONFIG.policy_rooms.iter().any(|room| room == room_id)
+}
+
+/// Loads the ban rules of a policy room, replacing the ones loaded before.
+pub async fn load_rules(client: &Client, room_id: &str) {
+    let Ok(id) = RoomId::parse_box(room_id) else {
+        println!("Invalid policy room {}", room_id);
+        return;
+    };
+    let state = match client
+        .send(get_state_events::v3::Request::new(id.into()), None)
+        .await
+    {
+        Ok(res) => res.room_state,
+        Err(err) => {
+            println!("Failed to get policy rules from {}: {}", room_id, err);
+            return;
+        }
+    };
+
+    let mut rules = vec![];
+    for event in state {
+        let Some(kind) = event
+            .get_field::<String>("type")
+            .ok()
+            .flatten()
+            .and_then(|event_type| policy_kind(&event_type))
+        else {
+            continue;
+        };
+        // Removed rules are left with empty content
+        let content = event
+            .get_field::<serde_json::Value>("content")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let recommendation = content["recommendation"].as_str().unwrap_or_default();
+        if recommendation != "m.ban" && recommendation != "org.matrix.mjolnir.ban" {
+            continue;
+        }
+        if let Some(entity) = content["entity"].as_str() {
+            rules.push(PolicyRule {
+                kind,
+                entity: entity.to_owned(),
+            });
+        }
+    }
+
+    println!("Loaded {} policy rules from {}", rules.len(), room_id);
+    RULES.lock().insert(room_id.to_owned(), rules);
+}
+
+/// Whether a user is banned by any of the policy rooms, either by their user id or their server.
+pub fn is_banned(user_id: &UserId) -> bool {
+    RULES.lock().values().flatten().any(|rule| match rule.kind {
+        PolicyKind::User => glob_matches(&rule.entity, user_id.as_str()),
+        PolicyKind::Server => glob_matches(&rule.entity, user_id.server_name().as_str()),
+    })
+}
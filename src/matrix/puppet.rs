@@ -0,0 +1,323 @@
+use anyhow::{anyhow, Result};
+use matrix_sdk::{room::Joined, Client};
+use ruma::{
+    api::client::membership::leave_room,
+    events::room::member::{MembershipState, RoomMemberEventContent},
+    OwnedMxcUri, OwnedUserId, RoomId, UserId,
+};
+
+use crate::{chat_service::User, CONFIG, DATABASE};
+
+use super::bot::{BOT_APPSERVICE, BOT_CLIENT, BOT_REGISTRATION};
+use super::encryption;
+
+/// Name puppets are shown with on Matrix.
+pub fn display_name(user: &User) -> String {
+    format!("{} ({})", user.display, user.tag)
+}
+
+fn cached_profile(user_id: &str, room_id: &str) -> Option<(String, Option<String>)> {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT display_name, avatar_url FROM puppet_names WHERE user_id=:uid AND room_id=:rid",
+            &[(":uid", user_id), (":rid", room_id)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .ok()
+}
+
+fn cache_profile(user_id: &str, room_id: &str, display_name: &str, avatar_url: Option<&str>) {
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO puppet_names (user_id, room_id, display_name, avatar_url) VALUES (?, ?, ?, ?)",
+            (user_id, room_id, display_name, avatar_url),
+        )
+        .expect("Failed to save puppet name to database!");
+}
+
+/// Uploads a Discord avatar to the media repo, reusing the upload if the same
+/// avatar was uploaded before.
+pub async fn avatar_mxc(puppet: &Client, avatar_url: &str) -> Option<OwnedMxcUri> {
+    let cached: Option<String> = DATABASE
+        .lock()
+        .query_row(
+            "SELECT mxc_uri FROM puppet_avatars WHERE discord_url=?",
+            [avatar_url],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(cached) = cached {
+        return Some(cached.into());
+    }
+
+    let mxc_uri = super::media::upload_url(puppet, avatar_url).await?;
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR REPLACE INTO puppet_avatars (discord_url, mxc_uri) VALUES (?, ?)",
+            (avatar_url, mxc_uri.as_str()),
+        )
+        .expect("Failed to save avatar to database!");
+    Some(mxc_uri)
+}
+
+/// Sets a puppet's name and avatar in a single room, as the same Discord user
+/// can have a different nickname in every guild. Only sends a member event if
+/// the profile changed.
+pub async fn set_room_profile(puppet: &Client, room: &Joined, user: &User) {
+    let user_id = puppet.user_id().unwrap();
+    let room_id = room.room_id().to_string();
+    let display_name = display_name(user);
+    let avatar_url = match &user.avatar {
+        Some(avatar) => avatar_mxc(puppet, avatar).await,
+        None => None,
+    };
+
+    let profile = (
+        display_name.clone(),
+        avatar_url.as_ref().map(|url| url.to_string()),
+    );
+    if cached_profile(user_id.as_str(), &room_id) == Some(profile) {
+        return;
+    }
+
+    let mut content = RoomMemberEventContent::new(MembershipState::Join);
+    content.displayname = Some(display_name.clone());
+    content.avatar_url = avatar_url.clone();
+
+    match room.send_state_event_for_key(user_id, content).await {
+        Ok(_) => cache_profile(
+            user_id.as_str(),
+            &room_id,
+            &display_name,
+            avatar_url.as_ref().map(|url| url.as_str()),
+        ),
+        Err(err) => println!("Failed to set profile in {}: {}", room_id, err),
+    }
+}
+
+fn is_registered(user_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM puppets WHERE user_id=?)",
+            [user_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+pub fn is_joined(user_id: &str, room_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM puppet_rooms WHERE user_id=? AND room_id=?)",
+            [user_id, room_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+async fn register(localpart: &str) {
+    let appservice_local = (*(BOT_APPSERVICE.lock().expect("Bot appservice is poisoned"))).clone();
+    let Some(appservice) = appservice_local else {
+        println!(
+            "Can't register {} before the appservice is running",
+            localpart
+        );
+        return;
+    };
+
+    // This also fails if the user already exists, which is fine
+    if let Err(err) = appservice.register_user(localpart, None).await {
+        println!("Failed to register {}: {}", localpart, err);
+    }
+}
+
+/// Remembers that a puppet joined a room.
+pub fn remember_room(user_id: &str, room_id: &str) -> rusqlite::Result<()> {
+    DATABASE.lock().execute(
+        "INSERT OR IGNORE INTO puppet_rooms (user_id, room_id) VALUES (?, ?)",
+        [user_id, room_id],
+    )?;
+    Ok(())
+}
+
+/// Gets the puppet for a Discord user, registering it the first time it's used.
+pub async fn get_puppet(discord_id: String) -> Client {
+    let registration_local = (*(BOT_REGISTRATION
+        .lock()
+        .expect("Bot registration is poisoned")))
+    .clone();
+    let appservice_local = (*(BOT_APPSERVICE.lock().expect("Bot appservice is poisoned"))).clone();
+
+    let relay_bot_name = format!(
+        "{}{}",
+        registration_local
+            .as_ref()
+            .unwrap()
+            .sender_localpart
+            .clone(),
+        discord_id
+    );
+
+    // Registered first, as puppets with encryption log in to get a device
+    let user_id = format!("@{}:{}", relay_bot_name, CONFIG.server_name);
+    if !is_registered(&user_id) {
+        register(&relay_bot_name).await;
+        DATABASE
+            .lock()
+            .execute(
+                "INSERT OR IGNORE INTO puppets (user_id) VALUES (?)",
+                [&user_id],
+            )
+            .expect("Failed to save puppet to database!");
+    }
+
+    return encryption::user(appservice_local.as_ref().unwrap(), &relay_bot_name)
+        .await
+        .unwrap();
+}
+
+/// Gets a room as a puppet, inviting and joining it only if it hasn't already.
+pub async fn get_room(puppet: &Client, room_id: &RoomId) -> Result<Joined> {
+    let user_id = puppet.user_id().unwrap();
+    // Puppet clients don't sync, so their store still has rooms they were
+    // kicked from or left. The database is checked first, and after a restart
+    // the store is empty so they join again, but the invite can be skipped
+    let was_joined = is_joined(user_id.as_str(), room_id.as_str());
+    if was_joined {
+        if let Some(room) = puppet.get_joined_room(room_id) {
+            return Ok(room);
+        }
+    } else {
+        let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+        if let Some(appservice_room) = client_local.unwrap().get_joined_room(room_id) {
+            // Fails if the puppet is already in the room
+            let _ = appservice_room.invite_user_by_id(user_id).await;
+        }
+    }
+
+    puppet.join_room_by_id(room_id).await?;
+    if !was_joined {
+        // Joining resets the puppet's profile in the room, so it has to be set again
+        forget_room(user_id.as_str(), room_id.as_str())?;
+    }
+    remember_room(user_id.as_str(), room_id.as_str())?;
+
+    puppet
+        .get_joined_room(room_id)
+        .ok_or_else(|| anyhow!("{} isn't in {} after joining", user_id, room_id))
+}
+
+/// Called when a puppet isn't allowed to do something in a room, which means
+/// the cache is out of date, e.g the puppet was kicked or the homeserver's
+/// database was reset. Registers the puppet again and forgets its membership,
+/// so the next `get_room` joins again.
+pub async fn repair(user_id: &UserId, room_id: &RoomId) {
+    println!("Repairing {} in {}", user_id, room_id);
+
+    register(user_id.localpart()).await;
+    // It joins again next time, so its room profile is forgotten too
+    let _ = forget_room(user_id.as_str(), room_id.as_str());
+}
+
+/// Removes a puppet from a room, e.g when the Discord user can no longer see the channel.
+pub async fn leave_room(puppet: &Client, room_id: &RoomId) -> Result<()> {
+    // The puppet client may not know about the room after a restart, so this
+    // doesn't go through the room. Its store isn't updated either, so forgetting
+    // the membership is what makes `get_room` join again later
+    puppet
+        .send(leave_room::v3::Request::new(room_id.to_owned()), None)
+        .await?;
+
+    forget_room(puppet.user_id().unwrap().as_str(), room_id.as_str())?;
+    Ok(())
+}
+
+/// Forgets a puppet's membership and profile in a room after it left or was removed.
+pub fn forget_room(user_id: &str, room_id: &str) -> rusqlite::Result<()> {
+    let database = DATABASE.lock();
+    database.execute(
+        "DELETE FROM puppet_rooms WHERE user_id=? AND room_id=?",
+        [user_id, room_id],
+    )?;
+    database.execute(
+        "DELETE FROM puppet_names WHERE user_id=? AND room_id=?",
+        [user_id, room_id],
+    )?;
+    Ok(())
+}
+
+/// The user id of a Discord user's puppet, without registering it.
+pub fn user_id(discord_id: &str) -> Option<OwnedUserId> {
+    let registration_local = (*(BOT_REGISTRATION
+        .lock()
+        .expect("Bot registration is poisoned")))
+    .clone()?;
+    UserId::parse(format!(
+        "@{}{}:{}",
+        registration_local.sender_localpart, discord_id, CONFIG.server_name
+    ))
+    .ok()
+}
+
+/// The Discord user a puppet belongs to, None if the user isn't a puppet.
+pub fn discord_id(user_id: &UserId) -> Option<String> {
+    let registration_local = (*(BOT_REGISTRATION
+        .lock()
+        .expect("Bot registration is poisoned")))
+    .clone()?;
+    let discord_id = user_id
+        .localpart()
+        .strip_prefix(&registration_local.sender_localpart)?;
+    // The bot itself has no Discord id
+    (!discord_id.is_empty()).then(|| discord_id.to_owned())
+}
+
+/// Makes a Discord user's puppet leave every room and forgets everything about it.
+pub async fn forget(discord_id: &str) {
+    let Some(user_id) = user_id(discord_id) else {
+        return;
+    };
+
+    // Avatars are found through the profiles, which leaving rooms deletes
+    let rooms: Vec<String> = {
+        let database = DATABASE.lock();
+        let _ = database.execute(
+            "DELETE FROM puppet_avatars WHERE mxc_uri IN (SELECT avatar_url FROM puppet_names WHERE user_id=?)",
+            [user_id.as_str()],
+        );
+        let mut stmt = database
+            .prepare("SELECT room_id FROM puppet_rooms WHERE user_id=?")
+            .unwrap();
+        let rooms = stmt
+            .query_map([user_id.as_str()], |row| row.get(0))
+            .unwrap()
+            .filter_map(|room| room.ok())
+            .collect();
+        rooms
+    };
+
+    if !rooms.is_empty() {
+        let puppet = get_puppet(discord_id.to_owned()).await;
+        for room in rooms {
+            let Ok(room_id) = RoomId::parse(&room) else {
+                continue;
+            };
+            if let Err(err) = leave_room(&puppet, &room_id).await {
+                println!("Puppet couldn't leave room: {}", err);
+            }
+        }
+    }
+
+    let database = DATABASE.lock();
+    for table in ["puppet_names", "puppet_rooms", "puppets"] {
+        let _ = database.execute(
+            &format!("DELETE FROM {table} WHERE user_id=?"),
+            [user_id.as_str()],
+        );
+    }
+}
@@ -1,67 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::{f32::consts::E, thread::panicking};
 
 use futures::future::Join;
 use matrix_sdk::{room::Joined, Client};
+use regex::Regex;
 use ruma::{
+    api::client::{
+        error::ErrorKind, membership::unban_user, message::send_message_event,
+        state::get_state_events_for_key,
+    },
     events::{
-        relation::{InReplyTo, Replacement},
-        room::message::{MessageType, Relation, RoomMessageEventContent},
+        macros::EventContent,
+        reaction::ReactionEventContent,
+        relation::{Annotation, InReplyTo, Replacement},
+        room::{
+            message::{
+                FileInfo, FileMessageEventContent, ImageMessageEventContent, MessageType, Relation,
+                RoomMessageEventContent,
+            },
+            power_levels::RoomPowerLevelsEventContent,
+            ImageInfo,
+        },
+        sticker::StickerEventContent,
+        MessageLikeEventContent, StateEventType,
     },
-    EventId, MxcUri, OwnedEventId, RoomId,
+    EventId, Int, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedMxcUri, OwnedUserId,
+    RoomId, TransactionId, UInt,
 };
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    chat_service::{self, FullMessage, Message},
-    CONFIG,
+    chat_service::{self, Attachment, FullMessage, Message, User},
+    link, CONFIG,
 };
 
-use super::bot::{BOT_APPSERVICE, BOT_CLIENT, BOT_REGISTRATION};
-
-async fn get_room_as_user(user: Client, room_id: &RoomId) -> Joined {
-    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-    let appservice_room = client_local.unwrap().get_joined_room(room_id);
-    appservice_room
-        .unwrap()
-        .invite_user_by_id(user.user_id().unwrap())
-        .await;
+use super::bot::BOT_CLIENT;
+use super::{dm, double_puppet, emotes, encryption, puppet};
 
-    user.join_room_by_id(room_id).await;
-    return user.get_joined_room(room_id).unwrap();
+/// A sticker replying to a message, as ruma's sticker content can't have a relation
+#[derive(Clone, Debug, Serialize, Deserialize, EventContent)]
+#[ruma_event(type = "m.sticker", kind = MessageLike)]
+struct ReplyStickerEventContent {
+    body: String,
+    info: ImageInfo,
+    url: OwnedMxcUri,
+    #[serde(rename = "m.relates_to")]
+    relates_to: ReplyRelation,
 }
 
-async fn get_bot_user(user_id: String) -> Client {
-    let registration_local = (*(BOT_REGISTRATION
-        .lock()
-        .expect("Bot registration is poisoned")))
-    .clone();
-    let appservice_local = (*(BOT_APPSERVICE.lock().expect("Bot appservice is poisoned"))).clone();
-
-    let relay_bot_name = format!(
-        "{}{}",
-        registration_local
-            .as_ref()
-            .unwrap()
-            .sender_localpart
-            .clone(),
-        user_id
-    );
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReplyRelation {
+    #[serde(rename = "m.in_reply_to")]
+    in_reply_to: InReplyTo,
+}
 
-    let res = appservice_local
-        .as_ref()
-        .unwrap()
-        .register_user(&relay_bot_name, None)
-        .await;
+lazy_static! {
+    static ref DISCORD_MENTION: Regex = Regex::new(r"<@!?(\d+)>").unwrap();
+    static ref POWER_LEVEL_LOCKS: parking_lot::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+        parking_lot::Mutex::new(HashMap::new());
+}
 
-    let user = appservice_local
-        .as_ref()
-        .unwrap()
-        .user(Some(&relay_bot_name))
-        .await
-        .unwrap();
-    return user;
+/// Turns Discord mentions of users with linked Matrix accounts into mentions of
+/// their Matrix accounts, returning the plain text and markdown versions.
+fn linked_mentions(content: &str) -> (String, String) {
+    let mut body = content.to_owned();
+    let mut markdown_body = content.to_owned();
+    for mention in DISCORD_MENTION.captures_iter(content) {
+        let Some(matrix_id) = link::linked_matrix_id(&mention[1]) else {
+            continue;
+        };
+        body = body.replace(&mention[0], &matrix_id);
+        markdown_body = markdown_body.replace(
+            &mention[0],
+            &format!("[{matrix_id}](https://matrix.to/#/{matrix_id})"),
+        );
+    }
+    (body, markdown_body)
 }
 
-pub async fn relay_message(message: FullMessage) -> Message {
+/// Relays a Discord message to the Matrix room or DM room its channel is bridged to.
+pub async fn relay_message(message: FullMessage) -> anyhow::Result<Message> {
     let mut out: Message = message.message.clone();
     for mroom in CONFIG.room.iter() {
         if mroom.discord.to_string() == message.message.room_id {
@@ -74,76 +93,264 @@ pub async fn relay_message(message: FullMessage) -> Message {
             break;
         }
     }
+    if out.service != "matrix" {
+        if let Some(room_id) = dm::room_for_channel(&message.message.room_id) {
+            out = Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id,
+                id: message.message.id.clone(),
+            };
+        }
+    }
 
-    let user = get_bot_user(message.user.id).await;
+    let id: Box<RoomId> = RoomId::parse_box(out.room_id.clone().as_ref())?;
+    let Some(room) = get_room_as_bot(&out.room_id) else {
+        anyhow::bail!("The bot isn't in {}", out.room_id);
+    };
 
-    let changed_name = user
-        .account()
-        .set_display_name(Some(
-            format!(
-                "{} ({})",
-                &message.user.display.clone(),
-                &message.user.tag.clone()
-            )
-            .as_str(),
-        ))
-        .await
-        .is_ok();
+    let reply_event = message
+        .reply
+        .clone()
+        .and_then(|reply_msg| chat_service::message_on_service(*reply_msg, "matrix"))
+        .and_then(|reply_msg| EventId::parse(reply_msg.id).ok());
 
-    if message.user.avatar.is_some() {
-        //user.account().set_avatar_url(uri);
+    let mut sticker = match &message.sticker {
+        Some(sticker) => emotes::sticker_content(sticker).await,
+        None => None,
+    };
+    // Attachments are uploaded, as Discord's links to them expire, or linked
+    // to if they can't be
+    let mut files = Vec::new();
+    let mut links = Vec::new();
+    for attachment in message.attachments.iter() {
+        match attachment_content(attachment).await {
+            Some(content) => files.push(content),
+            None => links.push(attachment.url.clone()),
+        }
+    }
+    // Stickers that can't be shown are sent as their name
+    let mut content = match &message.sticker {
+        Some(unshown) if sticker.is_none() && message.content.is_empty() => {
+            format!("[{} sticker]", unshown.name)
+        }
+        _ => message.content.clone(),
+    };
+    for link in links {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&link);
     }
 
-    let id: Box<RoomId> = RoomId::parse_box(out.room_id.clone().as_ref()).unwrap();
-
-    let room = get_room_as_user(user, id.as_ref()).await;
-    let content = RoomMessageEventContent::text_html(
-        message.content.clone(),
-        markdown::to_html(&message.content.clone()),
-    );
-
-    let mut reply_id: String = "".to_owned();
-    if message.reply.is_some() {
-        let reply_msg = *message.reply.unwrap();
+    if content.is_empty() {
+        let res = match (sticker.take(), &reply_event) {
+            (Some(sticker), Some(reply_event)) => {
+                let content = ReplyStickerEventContent {
+                    body: sticker.body,
+                    info: sticker.info,
+                    url: sticker.url,
+                    relates_to: ReplyRelation {
+                        in_reply_to: InReplyTo::new(reply_event.clone()),
+                    },
+                };
+                send_as_user(&message.user, id.as_ref(), content, message.timestamp).await?
+            }
+            (Some(sticker), None) => {
+                send_as_user(&message.user, id.as_ref(), sticker, message.timestamp).await?
+            }
+            (None, _) if !files.is_empty() => {
+                let file = files.remove(0);
+                let file = match reply_event.clone() {
+                    None => file,
+                    Some(reply_event) => reply_to_message(reply_event, file),
+                };
+                send_as_user(&message.user, id.as_ref(), file, message.timestamp).await?
+            }
+            (None, _) => anyhow::bail!("{} has nothing to relay", message.message.id),
+        };
+        out.id = res.to_string();
+        send_follow_ups(&message, &out, None, files).await;
+        return Ok(out);
+    }
 
-        let relayed_messages = chat_service::message_relays(reply_msg.clone());
+    let (body, markdown_body) = linked_mentions(&content);
+    let mut body = emotes::emoji_to_text(&body);
+    let mut html_body = emotes::emoji_to_html(&markdown::to_html(&markdown_body)).await;
 
-        if relayed_messages.len() > 0 {
-            for msg in relayed_messages.iter() {
-                if msg.service == "matrix" {
-                    reply_id = msg.id.clone();
+    match (&reply_event, &message.reply_preview) {
+        (Some(reply_event), Some(preview)) => {
+            // The sender on Matrix may be a puppet or a Matrix user, so ask the room
+            let sender = match room.event(reply_event).await {
+                Ok(event) => {
+                    serde_json::from_str::<serde_json::Value>(&event.event.json().to_string())
+                        .ok()
+                        .and_then(|v| v["sender"].as_str().map(|sender| sender.to_owned()))
                 }
+                Err(_) => None,
             }
-        } else {
-            let origin_message = chat_service::message_origin(reply_msg.clone());
-            if origin_message.is_some() {
-                reply_id = origin_message.unwrap().id;
-            }
+            .unwrap_or(preview.user.display.clone());
+
+            (body, html_body) = reply_fallback(
+                room.room_id().as_str(),
+                reply_event.as_str(),
+                &sender,
+                &preview.excerpt,
+                (body, html_body),
+            );
+        }
+        (None, Some(preview)) => {
+            // The replied to message was never bridged, so quote it instead
+            let author = format!("{} ({})", preview.user.display, preview.user.tag);
+            (body, html_body) = quote_fallback(&author, &preview.excerpt, (body, html_body));
         }
+        _ => {}
     }
 
-    if reply_id == "" {
-        let res = room.send(content, None).await;
-        out.id = res.unwrap().event_id.to_string();
+    let content = RoomMessageEventContent::text_html(body, html_body);
+
+    let content = match reply_event {
+        None => content,
+        Some(reply_event) => reply_to_message(reply_event, content),
+    };
+    let res = send_as_user(&message.user, id.as_ref(), content, message.timestamp).await?;
+    out.id = res.to_string();
+    send_follow_ups(&message, &out, sticker, files).await;
+    //let member = room.get_member(&user.user_id().unwrap()).await.unwrap().unwrap().
+    Ok(out)
+}
+
+/// Sends a message's sticker and files after its first event, saved so
+/// they're deleted along with the message.
+async fn send_follow_ups(
+    message: &FullMessage,
+    out: &Message,
+    sticker: Option<StickerEventContent>,
+    files: Vec<RoomMessageEventContent>,
+) {
+    let Ok(room_id) = RoomId::parse(&out.room_id) else {
+        return;
+    };
+    let save = |event_id: OwnedEventId| {
+        chat_service::create_message(
+            message.message.clone(),
+            Message {
+                id: event_id.to_string(),
+                ..out.clone()
+            },
+        )
+    };
+
+    if let Some(sticker) = sticker {
+        match send_as_user(&message.user, &room_id, sticker, message.timestamp).await {
+            Ok(event_id) => save(event_id),
+            Err(err) => println!("Failed to send sticker: {}", err),
+        }
+    }
+    for file in files {
+        match send_as_user(&message.user, &room_id, file, message.timestamp).await {
+            Ok(event_id) => save(event_id),
+            Err(err) => println!("Failed to send attachment: {}", err),
+        }
+    }
+}
+
+/// Uploads a Discord attachment and makes an m.image or m.file message for
+/// it, None if it couldn't be uploaded.
+async fn attachment_content(attachment: &Attachment) -> Option<RoomMessageEventContent> {
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let data = match reqwest::get(&attachment.url).await {
+        Ok(res) => res.bytes().await.ok()?.to_vec(),
+        Err(err) => {
+            println!("Failed to download attachment {}: {}", attachment.url, err);
+            return None;
+        }
+    };
+    let content_type = attachment
+        .content_type
+        .as_deref()
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let uploaded = match client_local?.media().upload(&content_type, data).await {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            println!("Failed to upload attachment {}: {}", attachment.url, err);
+            return None;
+        }
+    };
+
+    let message_type = if content_type.type_() == mime::IMAGE {
+        let mut info = ImageInfo::new();
+        info.mimetype = Some(content_type.to_string());
+        info.size = UInt::new(attachment.size);
+        MessageType::Image(ImageMessageEventContent::plain(
+            attachment.name.clone(),
+            uploaded.content_uri,
+            Some(Box::new(info)),
+        ))
     } else {
-        let res = reply_to_message(room, EventId::parse(reply_id).unwrap(), content).await;
-        out.id = res.to_string();
+        let mut info = FileInfo::new();
+        info.mimetype = Some(content_type.to_string());
+        info.size = UInt::new(attachment.size);
+        MessageType::File(FileMessageEventContent::plain(
+            attachment.name.clone(),
+            uploaded.content_uri,
+            Some(Box::new(info)),
+        ))
+    };
+    Some(RoomMessageEventContent::new(message_type))
+}
+
+/// Reacts to the Matrix copy of a message as a user, returning the reaction.
+pub async fn relay_reaction(
+    user: User,
+    reacted: Message,
+    key: String,
+    timestamp: Option<i64>,
+) -> Option<Message> {
+    let relayed_messages = chat_service::message_relays(reacted);
+    let relayed = relayed_messages
+        .iter()
+        .find(|msg| msg.service == "matrix")?;
+
+    let id: Box<RoomId> = RoomId::parse_box(relayed.room_id.as_ref()).ok()?;
+    let event_id = EventId::parse(relayed.id.clone()).ok()?;
+    let content = ReactionEventContent::new(Annotation::new(event_id, key));
+    match send_as_user(&user, id.as_ref(), content, timestamp).await {
+        Ok(event_id) => Some(Message {
+            id: event_id.to_string(),
+            ..relayed.clone()
+        }),
+        Err(err) => {
+            println!("Failed to send reaction: {}", err);
+            None
+        }
+    }
+}
+
+/// Redacts an event the bridge sent, as the bot.
+pub async fn delete_event(event: &Message) {
+    let (Some(room), Ok(event_id)) = (
+        get_room_as_bot(&event.room_id),
+        EventId::parse(event.id.clone()),
+    ) else {
+        println!("Can't redact {} in {}", event.id, event.room_id);
+        return;
+    };
+    if let Err(err) = room.redact(&event_id, None, None).await {
+        println!("Failed to redact {}: {}", event.id, err);
     }
-    //let member = room.get_member(&user.user_id().unwrap()).await.unwrap().unwrap().
-    return out;
 }
 
 pub async fn edit_message(message: FullMessage) {
-    let html_body = markdown::to_html(&message.content.clone());
-    let body = message.content.clone();
+    let html_body = emotes::emoji_to_html(&markdown::to_html(&message.content)).await;
+    let body = emotes::emoji_to_text(&message.content);
     let content = RoomMessageEventContent::text_html(body.clone(), html_body.clone());
     let relayed_messages = chat_service::message_relays(message.message);
-    let user = get_bot_user(message.user.id).await;
 
     for msg in relayed_messages.iter() {
         if msg.service == "matrix" {
             let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref()).unwrap();
-            let room = get_room_as_user(user.clone(), id.as_ref()).await;
             let event_id = EventId::parse(msg.id.clone()).unwrap();
 
             let replacement = Replacement::new(
@@ -152,70 +359,401 @@ pub async fn edit_message(message: FullMessage) {
             );
             let mut edited_content = content.clone();
             edited_content.relates_to = Some(Relation::Replacement(replacement));
-            room.timeline()
-                .await
-                .send(edited_content.into(), None)
-                .await;
+            if let Err(err) = send_as_user(&message.user, id.as_ref(), edited_content, None).await {
+                println!("Failed to edit message: {}", err);
+            }
         }
     }
 }
 
 pub async fn delete_message(message: Message) {
     let relayed_messages = chat_service::message_relays(message.clone());
-    if relayed_messages.len() > 0 {
-        for msg in relayed_messages {
-            if msg.service == "matrix" {
-                let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref()).unwrap();
-
-                let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-                let appservice_room = client_local.unwrap().get_joined_room(id.as_ref());
-
-                let event_id = EventId::parse_box(msg.id).unwrap();
-                let event_id_ref = &(*event_id);
-                appservice_room
-                    .unwrap()
-                    .redact(event_id_ref, None, None)
-                    .await;
-            }
+    for msg in relayed_messages {
+        if msg.service == "matrix" {
+            delete_event(&msg).await;
         }
     }
 
     let origin_message = chat_service::message_origin(message.clone());
-    if origin_message.is_some() && origin_message.clone().unwrap().clone().service == "matrix" {
-        let id: Box<RoomId> =
-            RoomId::parse_box(origin_message.clone().unwrap().room_id.clone().as_ref()).unwrap();
-
-        let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-        let appservice_room = client_local.unwrap().get_joined_room(id.as_ref());
-
-        let event_id = EventId::parse_box(origin_message.clone().unwrap().id).unwrap();
-        let event_id_ref = &(*event_id);
-        appservice_room
-            .unwrap()
-            .redact(event_id_ref, None, None)
-            .await;
+    if let Some(origin_message) = origin_message.filter(|origin| origin.service == "matrix") {
+        delete_event(&origin_message).await;
     }
 }
 
-async fn reply_to_message(
-    room: Joined,
+fn reply_to_message(
     event_id: OwnedEventId,
     content: RoomMessageEventContent,
-) -> OwnedEventId {
+) -> RoomMessageEventContent {
     let replacement = InReplyTo::new(event_id);
     let mut reply_content = content;
     reply_content.relates_to = Some(Relation::Reply {
         in_reply_to: replacement,
     });
-    let timeline = room.timeline().await;
-
-    let out = timeline.send(reply_content.into(), None).await;
-    return timeline
-        .latest_event()
-        .unwrap()
-        .as_local()
-        .unwrap()
-        .event_id()
-        .unwrap()
-        .to_owned();
+    return reply_content;
+}
+
+/// Sends an event as a Discord user, from their real Matrix account if they're
+/// double puppeted and otherwise from their puppet, with their profile.
+async fn send_as_user(
+    user: &User,
+    room_id: &RoomId,
+    content: impl MessageLikeEventContent + Clone,
+    timestamp: Option<i64>,
+) -> anyhow::Result<OwnedEventId> {
+    // Timestamps can't be set on real accounts, so they're sent as is. Real
+    // accounts can't encrypt, so the puppet is used in encrypted rooms
+    if !encryption::is_encrypted(room_id).await {
+        if let Some((client, room)) = double_puppet::for_room(&user.id, room_id).await {
+            return double_puppet::send(&client, &room, content).await;
+        }
+    }
+
+    let puppet = puppet::get_puppet(user.id.clone()).await;
+    let room = puppet::get_room(&puppet, room_id).await?;
+    puppet::set_room_profile(&puppet, &room, user).await;
+    send_as_puppet(&puppet, room_id, content, timestamp).await
+}
+
+/// Sends an event as a puppet, joining the room again and retrying once if
+/// the puppet isn't allowed to send there. Events in encrypted rooms are sent
+/// without their timestamp.
+async fn send_as_puppet(
+    user: &Client,
+    room_id: &RoomId,
+    content: impl MessageLikeEventContent + Clone,
+    timestamp: Option<i64>,
+) -> anyhow::Result<OwnedEventId> {
+    let room = puppet::get_room(user, room_id).await?;
+    // The timestamp is set with a raw request, which can't be encrypted
+    let timestamp = if CONFIG.encryption && encryption::is_encrypted(room_id).await {
+        encryption::prepare(user, room_id).await?;
+        None
+    } else {
+        timestamp
+    };
+    match send_with_timestamp(user, &room, content.clone(), timestamp).await {
+        Err(err) if err.client_api_error_kind() == Some(&ErrorKind::Forbidden) => {
+            puppet::repair(user.user_id().unwrap(), room_id).await;
+            let room = puppet::get_room(user, room_id).await?;
+            Ok(send_with_timestamp(user, &room, content, timestamp).await?)
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Sends a message with the appservice `ts` parameter, so that messages which
+/// were delayed (e.g retried or caught up on) still show when they were
+/// originally sent.
+async fn send_with_timestamp(
+    user: &Client,
+    room: &Joined,
+    content: impl MessageLikeEventContent,
+    timestamp: Option<i64>,
+) -> matrix_sdk::Result<OwnedEventId> {
+    let Some(timestamp) = timestamp.and_then(|ts| UInt::try_from(ts).ok()) else {
+        return Ok(room.send(content, None).await?.event_id);
+    };
+
+    let mut request = send_message_event::v3::Request::new(
+        room.room_id().to_owned(),
+        TransactionId::new(),
+        &content,
+    )
+    .expect("Should have serialized message!");
+    request.timestamp = Some(MilliSecondsSinceUnixEpoch(timestamp));
+
+    return Ok(user.send(request, None).await?.event_id);
+}
+
+/// Adds the fallback of a reply to a message's plain and HTML bodies, for
+/// clients that don't show replies.
+pub fn reply_fallback(
+    room_id: &str,
+    event_id: &str,
+    sender: &str,
+    excerpt: &str,
+    (body, html_body): (String, String),
+) -> (String, String) {
+    (
+        format!("> <{}> {}\n\n{}", sender, excerpt, body),
+        format!(
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> <a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote></mx-reply>{}",
+            room_id,
+            event_id,
+            sender,
+            html_escape(sender),
+            html_escape(excerpt),
+            html_body
+        ),
+    )
+}
+
+/// Quotes a message that was replied to but never bridged above the reply.
+pub fn quote_fallback(
+    author: &str,
+    excerpt: &str,
+    (body, html_body): (String, String),
+) -> (String, String) {
+    (
+        format!("> {}: {}\n\n{}", author, excerpt, body),
+        format!(
+            "<blockquote><b>{}</b>: {}</blockquote>{}",
+            html_escape(author),
+            html_escape(excerpt),
+            html_body
+        ),
+    )
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn get_room_as_bot(room_id: &str) -> Option<Joined> {
+    let id: Box<RoomId> = RoomId::parse_box(room_id).ok()?;
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    client_local?.get_joined_room(id.as_ref())
+}
+
+pub async fn send_notice(room_id: &str, notice: String) {
+    let Some(room) = get_room_as_bot(room_id) else {
+        return;
+    };
+    if let Err(err) = room
+        .send(RoomMessageEventContent::notice_plain(notice), None)
+        .await
+    {
+        println!("Failed to send notice: {}", err);
+    }
+}
+
+/// Makes sure a Discord user's puppet is in a room with an up to date profile.
+pub async fn join_puppet(user: User, room_id: &str, notice: bool) {
+    let puppet = puppet::get_puppet(user.id.clone()).await;
+    let id: Box<RoomId> = RoomId::parse_box(room_id).unwrap();
+    let was_joined = puppet::is_joined(puppet.user_id().unwrap().as_str(), room_id);
+
+    let room = match puppet::get_room(&puppet, id.as_ref()).await {
+        Ok(room) => room,
+        Err(err) => {
+            println!("Puppet couldn't join room: {}", err);
+            return;
+        }
+    };
+    puppet::set_room_profile(&puppet, &room, &user).await;
+
+    if notice && !was_joined {
+        send_notice(room_id, format!("{} joined on Discord", user.display)).await;
+    }
+}
+
+/// Removes a Discord user's puppet from a room, if it's in it.
+pub async fn leave_puppet(user: User, room_id: &str, notice: bool) {
+    // Checked without getting the puppet, which would register it
+    let Some(user_id) = puppet::user_id(&user.id) else {
+        return;
+    };
+    if !puppet::is_joined(user_id.as_str(), room_id) {
+        return;
+    }
+    let puppet = puppet::get_puppet(user.id.clone()).await;
+
+    let id: Box<RoomId> = RoomId::parse_box(room_id).unwrap();
+    if let Err(err) = puppet::leave_room(&puppet, id.as_ref()).await {
+        println!("Puppet couldn't leave room: {}", err);
+        return;
+    }
+
+    if notice {
+        send_notice(room_id, format!("{} left on Discord", user.display)).await;
+    }
+}
+
+/// Gives a Discord user's puppet a power level in a room, leaving the power
+/// levels untouched if it already has it.
+pub async fn set_puppet_power_level(discord_id: String, room_id: &str, level: i64) {
+    let level = Int::new_saturating(level);
+    update_puppet_power_level(discord_id, room_id, |_, _| level).await;
+}
+
+/// Mutes a Discord user's puppet in a room by dropping it below the level needed
+/// to send messages, or unmutes it by putting it back to the default level.
+pub async fn mute_puppet(discord_id: String, room_id: &str, muted: bool) {
+    update_puppet_power_level(discord_id, room_id, |content, current| {
+        if muted {
+            current.min(content.events_default - Int::from(1))
+        } else if current < content.events_default {
+            content.users_default
+        } else {
+            current
+        }
+    })
+    .await;
+}
+
+/// Sets the power levels of several puppets in a room at once, e.g when
+/// syncing a whole guild, with a single power levels event.
+pub async fn set_puppet_power_levels(room_id: &str, levels: Vec<(OwnedUserId, i64)>) {
+    update_power_levels(room_id, |content| {
+        levels.into_iter().fold(false, |changed, (user_id, level)| {
+            set_user_level(content, user_id, Int::new_saturating(level)) || changed
+        })
+    })
+    .await;
+}
+
+/// Changes a puppet's power level in a room to the level `level` returns given
+/// the room's power levels and the puppet's current level.
+async fn update_puppet_power_level(
+    discord_id: String,
+    room_id: &str,
+    level: impl FnOnce(&RoomPowerLevelsEventContent, Int) -> Int,
+) {
+    let puppet = puppet::get_puppet(discord_id).await;
+    let user_id = puppet.user_id().unwrap().to_owned();
+    update_power_levels(room_id, |content| {
+        let current = *content
+            .users
+            .get(&user_id)
+            .unwrap_or(&content.users_default);
+        let level = level(content, current);
+        set_user_level(content, user_id, level)
+    })
+    .await;
+}
+
+/// Sets a user's level in power levels, returning whether it changed.
+fn set_user_level(
+    content: &mut RoomPowerLevelsEventContent,
+    user_id: OwnedUserId,
+    level: Int,
+) -> bool {
+    let current = *content
+        .users
+        .get(&user_id)
+        .unwrap_or(&content.users_default);
+    if current == level {
+        return false;
+    }
+    if level == content.users_default {
+        content.users.remove(&user_id);
+    } else {
+        content.users.insert(user_id, level);
+    }
+    true
+}
+
+/// Changes a room's power levels with `change`, only sending them if it
+/// returns true.
+async fn update_power_levels(
+    room_id: &str,
+    change: impl FnOnce(&mut RoomPowerLevelsEventContent) -> bool,
+) {
+    let Some(room) = get_room_as_bot(room_id) else {
+        return;
+    };
+
+    // Power levels are replaced as a whole, so changes to a room wait for each other
+    let lock = POWER_LEVEL_LOCKS
+        .lock()
+        .entry(room_id.to_owned())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().await;
+
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let request = get_state_events_for_key::v3::Request::new(
+        room.room_id().to_owned(),
+        StateEventType::RoomPowerLevels,
+        "".to_owned(),
+    );
+    let mut content = match client_local.unwrap().send(request, None).await {
+        Ok(res) => match res.content.deserialize_as::<RoomPowerLevelsEventContent>() {
+            Ok(content) => content,
+            Err(err) => {
+                println!("Failed to parse power levels of {}: {}", room_id, err);
+                return;
+            }
+        },
+        Err(err) => {
+            println!("Failed to get power levels of {}: {}", room_id, err);
+            return;
+        }
+    };
+
+    if !change(&mut content) {
+        return;
+    }
+    if let Err(err) = room.send_state_event(content).await {
+        println!("Failed to set power levels of {}: {}", room_id, err);
+    }
+}
+
+/// Bans or unbans a Discord user's puppet in a room.
+pub async fn ban_puppet(discord_id: String, room_id: &str, banned: bool, reason: Option<&str>) {
+    let Some(room) = get_room_as_bot(room_id) else {
+        return;
+    };
+    let puppet = puppet::get_puppet(discord_id).await;
+    let user_id = puppet.user_id().unwrap();
+
+    let result = if banned {
+        room.ban_user(user_id, reason).await
+    } else {
+        let mut request =
+            unban_user::v3::Request::new(room.room_id().to_owned(), user_id.to_owned());
+        request.reason = reason.map(|reason| reason.to_owned());
+        room.client()
+            .send(request, None)
+            .await
+            .map(|_| ())
+            .map_err(matrix_sdk::Error::from)
+    };
+    if let Err(err) = result {
+        println!("Failed to ban or unban {} in {}: {}", user_id, room_id, err);
+        return;
+    }
+
+    // The puppet's store still has the room, so it's forgotten for `get_room`
+    // to join again once it's unbanned
+    if banned {
+        let _ = puppet::forget_room(user_id.as_str(), room_id);
+    }
+}
+
+pub fn room_name(room_id: &str) -> Option<String> {
+    get_room_as_bot(room_id)?.name()
+}
+
+pub async fn set_room_name(room_id: &str, name: String) {
+    let Some(room) = get_room_as_bot(room_id) else {
+        println!("Bot isn't in room {room_id}, can't set name");
+        return;
+    };
+
+    if room.name().as_deref() == Some(name.as_str()) {
+        return;
+    }
+
+    if let Err(err) = room.set_name(Some(name)).await {
+        println!("Failed to set room name: {}", err);
+    }
+}
+
+pub async fn set_room_topic(room_id: &str, topic: String) {
+    let Some(room) = get_room_as_bot(room_id) else {
+        println!("Bot isn't in room {room_id}, can't set topic");
+        return;
+    };
+
+    if room.topic().unwrap_or_default() == topic {
+        return;
+    }
+
+    if let Err(err) = room.set_room_topic(&topic).await {
+        println!("Failed to set room topic: {}", err);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::chat_service::{self, Message};
+use crate::{discord, filter, link, matrix, DATABASE};
+
+pub fn is_opted_out(service: &str, user_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM opt_outs WHERE service=? AND user_id=?)",
+            [service, user_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(false)
+}
+
+pub fn opt_out(service: &str, user_id: &str) {
+    DATABASE
+        .lock()
+        .execute(
+            "INSERT OR IGNORE INTO opt_outs (service, user_id, notified) VALUES (?, ?, 0)",
+            [service, user_id],
+        )
+        .expect("Failed to save opt out to database!");
+}
+
+/// Opts a user back in, returning whether they had opted out.
+pub fn opt_in(service: &str, user_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .execute(
+            "DELETE FROM opt_outs WHERE service=? AND user_id=?",
+            [service, user_id],
+        )
+        .map_or(false, |deleted| deleted > 0)
+}
+
+/// Whether the notice that an opted out user's messages aren't bridged still
+/// has to be shown, it's only shown for their first message after opting out.
+pub fn take_notice(service: &str, user_id: &str) -> bool {
+    DATABASE
+        .lock()
+        .execute(
+            "UPDATE opt_outs SET notified=1 WHERE service=? AND user_id=? AND notified=0",
+            [service, user_id],
+        )
+        .map_or(false, |updated| updated > 0)
+}
+
+/// Messages a user sent that were bridged.
+fn authored_messages(service: &str, user_id: &str) -> Vec<Message> {
+    let database = DATABASE.lock();
+    let mut stmt = database
+        .prepare(
+            "SELECT DISTINCT server_id_org, room_id_org, id_org FROM messages WHERE service_org=? AND author_org=?",
+        )
+        .unwrap();
+    stmt.query_map([service, user_id], |row| {
+        Ok(Message {
+            service: service.to_owned(),
+            server_id: row.get(0)?,
+            room_id: row.get(1)?,
+            id: row.get(2)?,
+        })
+    })
+    .unwrap()
+    .filter_map(|msg| msg.ok())
+    .collect()
+}
+
+/// How many messages bridged from a service aren't linked to who sent them,
+/// as they were bridged before authors were saved.
+fn unattributed_messages(service: &str) -> usize {
+    DATABASE
+        .lock()
+        .query_row(
+            "SELECT COUNT(DISTINCT id_org) FROM messages WHERE service_org=? AND author_org IS NULL",
+            [service],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+}
+
+/// Tells a user forgetting them can't cover older history, if there is any.
+pub fn unattributed_notice(service: &str) -> Option<String> {
+    let count = unattributed_messages(service);
+    if count == 0 {
+        return None;
+    }
+    Some(format!(
+        "{count} older bridged messages aren't linked to who sent them, so any of yours among them couldn't be removed. Ask the bridge's admins to remove them"
+    ))
+}
+
+/// Redacts the bridged copies of everything a user sent and forgets everything
+/// about them, apart from them having opted out so nothing new is bridged.
+/// Messages bridged before authors were saved can't be found, see
+/// unattributed_notice.
+pub async fn forget(service: &str, user_id: &str) {
+    let messages = authored_messages(service, user_id);
+    println!(
+        "Forgetting {} on {}, redacting {} messages",
+        user_id,
+        service,
+        messages.len()
+    );
+    for message in messages {
+        match service {
+            "discord" => matrix::relay::delete_message(message).await,
+            _ => discord::relay::delete_message(message).await,
+        }
+    }
+
+    // Reactions are bridged copies too
+    if service == "discord" {
+        for reaction in chat_service::take_user_reactions(user_id) {
+            matrix::relay::delete_event(&reaction).await;
+        }
+    } else {
+        for reaction in chat_service::matrix_user_reactions(user_id) {
+            discord::relay::remove_matrix_reaction(&reaction).await;
+        }
+    }
+    filter::forget(service, user_id);
+
+    {
+        let database = DATABASE.lock();
+        let _ = database.execute(
+            "DELETE FROM messages WHERE service_org=? AND author_org=?",
+            [service, user_id],
+        );
+        let _ = database.execute(
+            "DELETE FROM bans WHERE service=? AND user_id=?",
+            [service, user_id],
+        );
+        if service == "discord" {
+            let _ = database.execute("DELETE FROM dm_rooms WHERE discord_user=?", [user_id]);
+        }
+    }
+
+    if service == "discord" {
+        matrix::puppet::forget(user_id).await;
+        if let Some(matrix_id) = link::linked_matrix_id(user_id) {
+            link::unlink(&matrix_id);
+        }
+    } else {
+        link::unlink(user_id);
+        matrix::double_puppet::logout(user_id);
+    }
+
+    opt_out(service, user_id);
+}